@@ -0,0 +1,194 @@
+use std::io::{self, Read};
+use std::vec;
+use bio::alphabets::dna;
+use bio::io::fastq;
+use noodles::sam::alignment::record::Flags;
+use noodles::sam::alignment::RecordBuf;
+use noodles::{bam, cram, fasta, sam};
+
+/// Marker at the start of a decompressed BAM stream.
+pub const BAM_MAGIC: &[u8] = b"BAM\x01";
+
+/// Marker at the start of a CRAM file.
+pub const CRAM_MAGIC: &[u8] = b"CRAM";
+
+/// Iterator over the reads of an (unaligned) BAM stream as FASTQ records.
+///
+/// Secondary and supplementary records are skipped, and reads stored reverse
+/// complemented are flipped back to their sequenced orientation. Aligned
+/// reads keep their mapping quality and flags as [`Alignment`] tags. Mates share
+/// a name, by which [`Indexer::by_name`](crate::index::Indexer::by_name) pairs
+/// them wherever they are in the file.
+pub struct BamRecords<R> {
+    reader: bam::io::Reader<R>,
+    record: bam::Record,
+}
+
+impl<R: Read> BamRecords<R> {
+    /// Wrap an already decompressed BAM stream and read past its header.
+    pub fn new(input: R) -> io::Result<Self> {
+        let mut reader = bam::io::Reader::from(input);
+        reader.read_header()?;
+        Ok(BamRecords { reader, record: bam::Record::default() })
+    }
+}
+
+impl<R: Read> Iterator for BamRecords<R> {
+    type Item = io::Result<fastq::Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.reader.read_record(&mut self.record) {
+                Ok(0) => return None,
+                Ok(_) => {
+                    let flags = self.record.flags();
+                    if flags.is_secondary() || flags.is_supplementary() {
+                        continue;
+                    }
+                    let record = &self.record;
+                    let name = record.name().map(|name| name.to_string()).unwrap_or_default();
+                    let seq: Vec<u8> = record.sequence().iter().collect();
+                    let alignment = Alignment { mapping_quality: record.mapping_quality().map(|q| q.get()), flags };
+                    return Some(Ok(to_fastq(&name, alignment, seq, record.quality_scores().as_bytes())));
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Iterator over the reads of a CRAM stream as FASTQ records, with the same
+/// filtering and orientation rules as [`BamRecords`].
+///
+/// Unaligned CRAM stores bases verbatim; a reference is only needed when
+/// reads were compressed against one.
+pub struct CramRecords<R> {
+    reader: cram::io::Reader<R>,
+    header: sam::Header,
+    repository: fasta::Repository,
+    records: vec::IntoIter<RecordBuf>,
+}
+
+impl<R: Read> CramRecords<R> {
+    /// Read past the CRAM header, resolving reference sequences from the
+    /// FASTA at `reference` (which needs a `.fai` index) if given.
+    pub fn new(input: R, reference: Option<&str>) -> io::Result<Self> {
+        let repository = match reference {
+            Some(path) => {
+                let reader = fasta::io::indexed_reader::Builder::default().build_from_path(path)?;
+                fasta::Repository::new(fasta::repository::adapters::IndexedReader::new(reader))
+            }
+            None => fasta::Repository::default(),
+        };
+        let mut reader = cram::io::reader::Builder::default()
+            .set_reference_sequence_repository(repository.clone())
+            .build_from_reader(input);
+        let header = reader.read_header()?;
+        Ok(CramRecords { reader, header, repository, records: Vec::new().into_iter() })
+    }
+
+    /// Decode the next container into `records`. Returns `false` at EOF.
+    fn read_container(&mut self) -> io::Result<bool> {
+        let mut container = cram::io::reader::Container::default();
+        if self.reader.read_container(&mut container)? == 0 {
+            return Ok(false);
+        }
+
+        let compression_header = container.compression_header()?;
+        let mut records = Vec::new();
+        for slice in container.slices() {
+            let slice = slice?;
+            let (core_data_src, external_data_srcs) = slice.decode_blocks()?;
+            for record in slice.records(
+                self.repository.clone(),
+                &self.header,
+                &compression_header,
+                &core_data_src,
+                &external_data_srcs,
+            )? {
+                records.push(RecordBuf::try_from_alignment_record(&self.header, &record)?);
+            }
+        }
+        self.records = records.into_iter();
+        Ok(true)
+    }
+}
+
+impl<R: Read> Iterator for CramRecords<R> {
+    type Item = io::Result<fastq::Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.records.next() {
+                Some(record) => {
+                    let flags = record.flags();
+                    if flags.is_secondary() || flags.is_supplementary() {
+                        continue;
+                    }
+                    let name = record.name().map(|name| name.to_string()).unwrap_or_default();
+                    let seq = record.sequence().as_ref().to_vec();
+                    let alignment = Alignment { mapping_quality: record.mapping_quality().map(|q| q.get()), flags };
+                    return Some(Ok(to_fastq(&name, alignment, seq, record.quality_scores().as_ref())));
+                }
+                None => match self.read_container() {
+                    Ok(true) => {}
+                    Ok(false) => return None,
+                    Err(e) => return Some(Err(e)),
+                },
+            }
+        }
+    }
+}
+
+/// Mapping quality and flags of an aligned record, carried into the FASTQ
+/// description as the local-use SAM tags `mq:i:` and `fl:i:`, e.g.
+/// `mq:i:60 fl:i:99`. The mapping quality is left out when unavailable (255).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Alignment {
+    pub mapping_quality: Option<u8>,
+    pub flags: Flags,
+}
+
+impl Alignment {
+    /// Parse the tags written for an aligned record back from a description.
+    pub fn from_description(desc: &str) -> Option<Self> {
+        let mut mapping_quality = None;
+        let mut flags = None;
+        for field in desc.split_whitespace() {
+            if let Some(value) = field.strip_prefix("mq:i:") {
+                mapping_quality = value.parse().ok();
+            } else if let Some(value) = field.strip_prefix("fl:i:") {
+                flags = value.parse().ok().map(Flags::from_bits_retain);
+            }
+        }
+        Some(Alignment { mapping_quality, flags: flags? })
+    }
+
+    fn description(&self) -> String {
+        match self.mapping_quality {
+            Some(mapping_quality) => format!("mq:i:{} fl:i:{}", mapping_quality, self.flags.bits()),
+            None => format!("fl:i:{}", self.flags.bits()),
+        }
+    }
+}
+
+/// Build a FASTQ record from alignment fields, restoring the sequenced
+/// orientation of reverse-complemented reads. Unmapped reads, as in uBAM,
+/// get no description.
+fn to_fastq(name: &str, alignment: Alignment, mut seq: Vec<u8>, raw_qual: &[u8]) -> fastq::Record {
+    let flags = alignment.flags;
+    // Missing qualities are stored as 0xff; treat them like FASTA input.
+    let mut qual: Vec<u8> = if raw_qual.iter().all(|&q| q == 0xff) {
+        Vec::new()
+    } else {
+        raw_qual.iter().map(|q| q + 33).collect()
+    };
+
+    if flags.is_reverse_complemented() {
+        seq = dna::revcomp(&seq);
+        qual.reverse();
+    }
+
+    let desc = (!flags.is_unmapped()).then(|| alignment.description());
+    fastq::Record::with_attrs(name, desc.as_deref(), &seq, &qual)
+}
@@ -0,0 +1,52 @@
+use std::io::{self, BufRead, BufReader};
+use clusterpluk::input::open_input;
+
+/// One row of a samplesheet.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub name: String,
+    pub r1: String,
+    /// Empty for single-end samples.
+    pub r2: Option<String>,
+    pub cluster: String,
+    pub out_prefix: String,
+}
+
+/// Read a CSV or TSV samplesheet with `sample, R1, R2, clstr, out_prefix`
+/// columns. The delimiter is a tab if the first line contains one, a comma
+/// otherwise. A header row starting with `sample`, blank lines and `#`
+/// comments are skipped.
+pub fn read_samplesheet(path: &str) -> io::Result<Vec<Sample>> {
+    let reader = BufReader::new(open_input(path)?);
+    let mut samples = Vec::new();
+    let mut delimiter = None;
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let delimiter = *delimiter.get_or_insert(if line.contains('\t') { '\t' } else { ',' });
+        let fields: Vec<&str> = line.split(delimiter).map(str::trim).collect();
+        if samples.is_empty() && fields[0].eq_ignore_ascii_case("sample") {
+            continue;
+        }
+
+        let [name, r1, r2, cluster, out_prefix] = fields[..] else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} line {}: expected 5 columns, found {}", path, i + 1, fields.len()),
+            ));
+        };
+        samples.push(Sample {
+            name: name.to_string(),
+            r1: r1.to_string(),
+            r2: (!r2.is_empty()).then(|| r2.to_string()),
+            cluster: cluster.to_string(),
+            out_prefix: out_prefix.to_string(),
+        });
+    }
+
+    Ok(samples)
+}
@@ -0,0 +1,209 @@
+use std::io::{self, BufRead, Lines};
+use log::{debug, warn};
+use crate::clusters::{Cluster, Member, Strand};
+
+/// Streams the clusters of a cd-hit `.clstr` file, one `>Cluster` block at a
+/// time, so the cluster file never has to be held in memory.
+pub struct ClstrClusters<R> {
+    lines: Lines<R>,
+    /// Number of lines read so far.
+    line_number: usize,
+    /// The cluster being collected, `None` before the first one and once the
+    /// file is exhausted.
+    current: Option<Cluster>,
+}
+
+impl<R: BufRead> ClstrClusters<R> {
+    pub fn new(reader: R) -> Self {
+        ClstrClusters { lines: reader.lines(), line_number: 0, current: None }
+    }
+}
+
+impl<R: BufRead> Iterator for ClstrClusters<R> {
+    type Item = io::Result<Cluster>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for line in self.lines.by_ref() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e)),
+            };
+            self.line_number += 1;
+            // Tolerate CRLF line endings and stray surrounding whitespace
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(header) = line.strip_prefix('>') {
+                let id = header.strip_prefix("Cluster").unwrap_or(header).trim().to_string();
+                let next = Cluster { id, members: Vec::new() };
+                if let Some(cluster) = self.current.replace(next) {
+                    return Some(Ok(cluster));
+                }
+            } else if let Some(member) = parse_member(line) {
+                // Members before any `>Cluster` line belong to cd-hit's cluster 0
+                let cluster = self.current.get_or_insert_with(|| Cluster { id: "0".to_string(), members: Vec::new() });
+                cluster.members.push(member);
+            } else if self.line_number == 1 {
+                debug!("Skipping header line: {}", line);
+            } else {
+                warn!("Malformed line: {}", line);
+            }
+        }
+        self.current.take().map(Ok)
+    }
+}
+
+/// Parse a member line of a `.clstr` file. The layouts written by the cd-hit
+/// family are
+///
+/// ```text
+/// 0   100aa, >seq1... *                      representative
+/// 1   98aa, >seq2... at 95.92%               cd-hit
+/// 1   25nt, >read2... at +/100.00%           cd-hit-est, cd-hit-dup
+/// 1   25nt, >read2... at 1:25:1:25/+/100.00% cd-hit-est -p 1, cd-hit-454
+/// ```
+///
+/// Read names are cut at the last `...` before the marker, so names that
+/// themselves contain `...` survive.
+pub(crate) fn parse_member(line: &str) -> Option<Member> {
+    let (number, rest) = line.split_once(char::is_whitespace)?;
+    if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // The length ("25nt," or "100aa,") runs up to the '>' opening the name
+    let (_length, rest) = rest.split_once('>')?;
+    let rest = rest.trim_end();
+
+    let (id, member) = if let Some(name) = rest.strip_suffix('*') {
+        let id = name.trim_end().strip_suffix("...")?;
+        (id, Member::representative(id))
+    } else if let Some((id, alignment)) = split_at_marker(rest) {
+        let (strand, identity) = parse_alignment(alignment)?;
+        (id, Member { identity: Some(identity), strand, ..Member::new(id) })
+    } else {
+        let id = rest.strip_suffix("...")?;
+        (id, Member::new(id))
+    };
+    if id.trim().is_empty() { None } else { Some(Member { id: id.trim().to_string(), ..member }) }
+}
+
+/// Split `name... at <alignment>` at the last `...` followed by `at`.
+fn split_at_marker(rest: &str) -> Option<(&str, &str)> {
+    rest.rmatch_indices("...").find_map(|(i, _)| {
+        let alignment = rest[i + 3..].trim_start().strip_prefix("at")?;
+        alignment.starts_with(char::is_whitespace).then_some((&rest[..i], alignment))
+    })
+}
+
+/// Parse the part after `at`, e.g. `95.92%`, `+/100.00%` or
+/// `1:25:1:25/-/98.00%`, into the strand (if given) and percent identity.
+fn parse_alignment(alignment: &str) -> Option<(Option<Strand>, f64)> {
+    let mut parts = alignment.trim().rsplit('/');
+    let identity = parts.next()?.trim().trim_end_matches('%').parse().ok()?;
+    let strand = match parts.next().map(str::trim) {
+        Some("+") => Some(Strand::Forward),
+        Some("-") => Some(Strand::Reverse),
+        None => None,
+        Some(_) => return None,
+    };
+    Some((strand, identity))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(line: &str) -> Member {
+        parse_member(line).unwrap_or_else(|| panic!("failed to parse {:?}", line))
+    }
+
+    #[test]
+    fn representative() {
+        let m = member("0\t100aa, >sp|P12345|PROT... *");
+        assert_eq!(m.id, "sp|P12345|PROT");
+        assert!(m.representative);
+        assert_eq!(m.identity, None);
+        assert_eq!(m.strand, None);
+    }
+
+    #[test]
+    fn cd_hit_protein_identity() {
+        let m = member("1\t98aa, >seq2... at 95.92%");
+        assert_eq!(m.id, "seq2");
+        assert!(!m.representative);
+        assert_eq!(m.identity, Some(95.92));
+        assert_eq!(m.strand, None);
+    }
+
+    #[test]
+    fn cd_hit_est_strand() {
+        let forward = member("1\t25nt, >read2... at +/100.00%");
+        assert_eq!(forward.id, "read2");
+        assert_eq!(forward.strand, Some(Strand::Forward));
+        assert_eq!(forward.identity, Some(100.0));
+
+        let reverse = member("2\t25nt, >read3... at -/98.50%");
+        assert_eq!(reverse.strand, Some(Strand::Reverse));
+        assert_eq!(reverse.identity, Some(98.5));
+    }
+
+    #[test]
+    fn alignment_coordinates() {
+        let m = member("1\t250nt, >r2... at 1:250:3:252/-/99.20%");
+        assert_eq!(m.id, "r2");
+        assert_eq!(m.strand, Some(Strand::Reverse));
+        assert_eq!(m.identity, Some(99.2));
+    }
+
+    #[test]
+    fn name_containing_dots() {
+        let m = member("1\t150nt, >M01:run...x... at +/99.33%");
+        assert_eq!(m.id, "M01:run...x");
+        let rep = member("0\t150nt, >a...b... *");
+        assert_eq!(rep.id, "a...b");
+    }
+
+    #[test]
+    fn whitespace_and_crlf() {
+        let m = member("1\t25nt, >read2...   at   100.00%  \r");
+        assert_eq!(m.id, "read2");
+        assert_eq!(m.identity, Some(100.0));
+        assert_eq!(member("0  25nt, >read1...  * ").id, "read1");
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(parse_member("not a member line").is_none());
+        assert!(parse_member("1\t25nt, >read2 at 100%").is_none());
+        assert!(parse_member("1\t25nt, >... *").is_none());
+        assert!(parse_member("1\t25nt, >read2... at x/100%").is_none());
+    }
+
+    #[test]
+    fn clusters_are_grouped_and_the_last_is_kept() {
+        let clstr = ">Cluster 0\n0\t25nt, >read1... *\n1\t25nt, >read2... at +/100.00%\n\
+                     >Cluster 1\n0\t25nt, >read3... *\n";
+        let clusters: Vec<Cluster> = ClstrClusters::new(clstr.as_bytes()).map(Result::unwrap).collect();
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].id, "0");
+        let ids: Vec<&str> = clusters[0].members.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(ids, ["read1", "read2"]);
+        assert_eq!(clusters[1].id, "1");
+        assert_eq!(clusters[1].representative().map(|m| m.id.as_str()), Some("read3"));
+    }
+
+    #[test]
+    fn first_cluster_is_kept_with_or_without_a_header_line() {
+        let clstr = ">Cluster 0\n0\t25nt, >read1... *\n>Cluster 1\n0\t25nt, >read2... *\n";
+        let with_header = format!("cd-hit-est clusters\n{}", clstr);
+        let headerless = "0\t25nt, >read1... *\n>Cluster 1\n0\t25nt, >read2... *\n";
+        for input in [clstr, with_header.as_str(), headerless] {
+            let clusters: Vec<Cluster> = ClstrClusters::new(input.as_bytes()).map(Result::unwrap).collect();
+            let ids: Vec<&str> = clusters.iter().map(|c| c.id.as_str()).collect();
+            assert_eq!(ids, ["0", "1"], "{:?}", input);
+            assert_eq!(clusters[0].members[0].id, "read1");
+        }
+    }
+}
@@ -0,0 +1,563 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, Read};
+use clap::ValueEnum;
+use log::{info, warn};
+use serde::Deserialize;
+use crate::clstr::{parse_member, ClstrClusters};
+
+/// Cluster file formats selectable with `--cluster-format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ClusterFileFormat {
+    /// Detect the format from the first lines of the file
+    Auto,
+    /// cd-hit / cd-hit-est `.clstr`
+    Clstr,
+    /// VSEARCH / USEARCH `.uc`
+    Uc,
+    /// MMseqs2 `easy-cluster` / `createtsv` `representative<TAB>member` TSV
+    Mmseqs,
+    /// swarm: one line of whitespace-separated amplicon IDs per cluster
+    Swarm,
+    /// isONclust `final_clusters.tsv`: `cluster_id<TAB>read_id` lines
+    Isonclust,
+    /// Generic `read_id<TAB>cluster_id` TSV, for any other clustering tool
+    Tsv,
+    /// starcode `--print-clusters`: `centroid<TAB>count<TAB>member,member,...`
+    Starcode,
+    /// JSON array of clusters, or JSON Lines with one cluster per line. Each
+    /// cluster is `{"id": "...", "members": ["read", ...], "representative": "read"}`;
+    /// `id` and `representative` are optional
+    Json,
+}
+
+impl ClusterFileFormat {
+    /// Name of the format in a [`FormatRegistry`], `None` for [`ClusterFileFormat::Auto`].
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            ClusterFileFormat::Auto => None,
+            ClusterFileFormat::Clstr => Some("clstr"),
+            ClusterFileFormat::Uc => Some("uc"),
+            ClusterFileFormat::Mmseqs => Some("mmseqs"),
+            ClusterFileFormat::Swarm => Some("swarm"),
+            ClusterFileFormat::Isonclust => Some("isonclust"),
+            ClusterFileFormat::Tsv => Some("tsv"),
+            ClusterFileFormat::Starcode => Some("starcode"),
+            ClusterFileFormat::Json => Some("json"),
+        }
+    }
+}
+
+/// One cluster read from a cluster file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cluster {
+    /// Cluster name or number as written in the cluster file.
+    pub id: String,
+    /// The members, in file order.
+    pub members: Vec<Member>,
+}
+
+/// A cluster member as listed in a cluster file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    /// Read ID of the member.
+    pub id: String,
+    /// Whether the clustering tool chose this member to represent the cluster,
+    /// e.g. the `*` member of a cd-hit cluster or a VSEARCH centroid.
+    pub representative: bool,
+    /// Percent identity to the representative, if the format records it.
+    pub identity: Option<f64>,
+    /// Strand the member aligned on relative to the representative, if the
+    /// format records it.
+    pub strand: Option<Strand>,
+}
+
+/// Orientation of a member relative to its cluster representative.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strand {
+    Forward,
+    Reverse,
+}
+
+impl Member {
+    pub fn new(id: impl Into<String>) -> Self {
+        Member { id: id.into(), representative: false, identity: None, strand: None }
+    }
+
+    pub fn representative(id: impl Into<String>) -> Self {
+        Member { representative: true, ..Member::new(id) }
+    }
+}
+
+impl Cluster {
+    /// The member marked as representative, if the format records one.
+    pub fn representative(&self) -> Option<&Member> {
+        self.members.iter().find(|member| member.representative)
+    }
+}
+
+/// Clusters parsed from a cluster file.
+pub type Clusters = Box<dyn Iterator<Item = io::Result<Cluster>>>;
+
+/// A cluster file format. Implement it to read clusters written by a tool
+/// that is not supported out of the box, and add it to a [`FormatRegistry`].
+pub trait ClusterFormat {
+    /// Name of the format, e.g. `clstr`.
+    fn name(&self) -> &str;
+
+    /// Whether `line`, the first line of a file that is neither blank nor a
+    /// `#` comment, looks like this format.
+    fn sniff(&self, line: &str) -> bool;
+
+    /// Whether a file whose first lines that are neither blank nor a `#`
+    /// comment are `lines` (at most two) looks like this format. Formats
+    /// that can start with a header line override this; by default only the
+    /// first line is sniffed.
+    fn sniff_lines(&self, lines: &[String]) -> bool {
+        lines.first().is_some_and(|line| self.sniff(line))
+    }
+
+    /// Parse a cluster file into its clusters.
+    fn parse(&self, reader: Box<dyn BufRead>) -> io::Result<Clusters>;
+}
+
+/// The cluster formats available by name and for detection.
+pub struct FormatRegistry {
+    /// In detection order: registered formats first, then the built-in ones
+    /// from most to least specific.
+    formats: Vec<Box<dyn ClusterFormat>>,
+}
+
+impl Default for FormatRegistry {
+    /// A registry of the built-in formats.
+    fn default() -> Self {
+        FormatRegistry {
+            formats: vec![
+                Box::new(Clstr),
+                Box::new(Json),
+                Box::new(Uc),
+                Box::new(Starcode),
+                Box::new(Mmseqs),
+                Box::new(Isonclust),
+                Box::new(Tsv),
+                Box::new(Swarm),
+            ],
+        }
+    }
+}
+
+impl FormatRegistry {
+    /// Add a format. It is sniffed before the formats already registered and
+    /// replaces any of them with the same name.
+    pub fn register(&mut self, format: impl ClusterFormat + 'static) {
+        self.formats.insert(0, Box::new(format));
+    }
+
+    /// The format with the given name.
+    pub fn get(&self, name: &str) -> Option<&dyn ClusterFormat> {
+        self.formats.iter().find(|format| format.name() == name).map(|format| format.as_ref())
+    }
+
+    /// Parse a cluster file in the named format, or detect the format from
+    /// the first record lines when `name` is `None`. A file no format
+    /// recognises is read as swarm, the most permissive one, with a warning.
+    pub fn read<R: BufRead + 'static>(&self, reader: R, name: Option<&str>) -> io::Result<Clusters> {
+        let Some(name) = name else {
+            let (lines, reader) = first_record_lines(reader, 2)?;
+            let first = lines.first().map_or("", String::as_str);
+            let format = match self.formats.iter().find(|format| format.sniff_lines(&lines)) {
+                Some(format) => format.as_ref(),
+                None => {
+                    let unrecognised = format!("Unrecognised cluster file format: {}", first);
+                    let swarm =
+                        self.get("swarm").ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, unrecognised))?;
+                    warn!("Unrecognised cluster file format, reading it as swarm (pass --cluster-format): {}", first);
+                    swarm
+                }
+            };
+            info!("\nDetected cluster file format: {}", format.name());
+            return format.parse(Box::new(reader));
+        };
+        match self.get(name) {
+            Some(format) => format.parse(Box::new(reader)),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown cluster format: {}", name))),
+        }
+    }
+}
+
+/// Parse a cluster file in one of the built-in formats, detecting it first
+/// for [`ClusterFileFormat::Auto`].
+pub fn read_clusters<R: BufRead + 'static>(reader: R, format: ClusterFileFormat) -> io::Result<Clusters> {
+    FormatRegistry::default().read(reader, format.name())
+}
+
+/// Chain the clusters of several cluster files, e.g. of data clustered in
+/// chunks, renumbering them from 0 so IDs repeated across files stay unique.
+pub fn merge_clusters(files: Vec<Clusters>) -> Clusters {
+    Box::new(files.into_iter().flatten().enumerate().map(|(i, cluster)| {
+        cluster.map(|cluster| Cluster { id: i.to_string(), ..cluster })
+    }))
+}
+
+/// A stream with its sniffed leading lines put back in front.
+type Sniffed<R> = io::Chain<io::Cursor<Vec<u8>>, R>;
+
+/// Read up to the first `count` lines that are neither blank nor a `#`
+/// comment, and hand back those lines along with a reader that still yields
+/// everything read.
+fn first_record_lines<R: BufRead>(mut reader: R, count: usize) -> io::Result<(Vec<String>, Sniffed<R>)> {
+    let mut head = Vec::new();
+    let mut lines = Vec::new();
+    while lines.len() < count {
+        let start = head.len();
+        if reader.read_until(b'\n', &mut head)? == 0 {
+            break;
+        }
+        let line = String::from_utf8_lossy(&head[start..]).trim().to_string();
+        if !line.is_empty() && !line.starts_with('#') {
+            lines.push(line);
+        }
+    }
+    Ok((lines, io::Cursor::new(head).chain(reader)))
+}
+
+/// The tab-separated fields of a line, trimmed.
+fn tab_fields(line: &str) -> Vec<&str> {
+    line.split('\t').map(str::trim).collect()
+}
+
+fn is_number(field: &str) -> bool {
+    !field.is_empty() && field.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// cd-hit / cd-hit-est `.clstr`, recognised by its `>Cluster` header.
+pub struct Clstr;
+
+impl ClusterFormat for Clstr {
+    fn name(&self) -> &str {
+        "clstr"
+    }
+
+    fn sniff(&self, line: &str) -> bool {
+        // A `>Cluster` line, or the first member of a file written without one
+        line.starts_with('>') || parse_member(line).is_some()
+    }
+
+    fn sniff_lines(&self, lines: &[String]) -> bool {
+        // A leading header line is skipped, as `ClstrClusters` does
+        match lines {
+            [first, ..] if self.sniff(first) => true,
+            [_, second] => second.starts_with(">Cluster") || parse_member(second).is_some(),
+            _ => false,
+        }
+    }
+
+    fn parse(&self, reader: Box<dyn BufRead>) -> io::Result<Clusters> {
+        Ok(Box::new(ClstrClusters::new(reader)))
+    }
+}
+
+/// VSEARCH / USEARCH `.uc`, recognised by its record type and 10 columns.
+pub struct Uc;
+
+impl ClusterFormat for Uc {
+    fn name(&self) -> &str {
+        "uc"
+    }
+
+    fn sniff(&self, line: &str) -> bool {
+        let fields = tab_fields(line);
+        fields.len() >= 9 && ["S", "H", "C", "N"].contains(&fields[0])
+    }
+
+    fn parse(&self, reader: Box<dyn BufRead>) -> io::Result<Clusters> {
+        Ok(Box::new(read_uc(reader)?.into_iter().map(Ok)))
+    }
+}
+
+/// MMseqs2 `representative<TAB>member` TSV, recognised by its first line
+/// mapping the representative to itself.
+pub struct Mmseqs;
+
+impl ClusterFormat for Mmseqs {
+    fn name(&self) -> &str {
+        "mmseqs"
+    }
+
+    fn sniff(&self, line: &str) -> bool {
+        matches!(tab_fields(line).as_slice(), [first, second] if first == second)
+    }
+
+    fn parse(&self, reader: Box<dyn BufRead>) -> io::Result<Clusters> {
+        let clusters = group_tsv(reader, true, |fields| Some((fields.first()?, fields.get(1)?)))?;
+        Ok(Box::new(clusters.into_iter().map(Ok)))
+    }
+}
+
+/// swarm, one line of space-separated IDs per cluster, recognised by its
+/// lack of tabs. It is tried last, and files no format recognises are read
+/// as swarm as well.
+pub struct Swarm;
+
+impl ClusterFormat for Swarm {
+    fn name(&self) -> &str {
+        "swarm"
+    }
+
+    fn sniff(&self, line: &str) -> bool {
+        !line.contains('\t') && !line.starts_with(['>', '[', '{'])
+    }
+
+    fn parse(&self, reader: Box<dyn BufRead>) -> io::Result<Clusters> {
+        Ok(Box::new(read_swarm(reader)))
+    }
+}
+
+/// isONclust `cluster_id<TAB>read_id`, recognised by its numeric first column.
+pub struct Isonclust;
+
+impl ClusterFormat for Isonclust {
+    fn name(&self) -> &str {
+        "isonclust"
+    }
+
+    fn sniff(&self, line: &str) -> bool {
+        matches!(tab_fields(line).as_slice(), [first, _] if is_number(first))
+    }
+
+    fn parse(&self, reader: Box<dyn BufRead>) -> io::Result<Clusters> {
+        let clusters = group_tsv(reader, false, |fields| Some((fields.first()?, fields.get(1)?)))?;
+        Ok(Box::new(clusters.into_iter().map(Ok)))
+    }
+}
+
+/// Generic `read_id<TAB>cluster_id` TSV, recognised by its two columns.
+pub struct Tsv;
+
+impl ClusterFormat for Tsv {
+    fn name(&self) -> &str {
+        "tsv"
+    }
+
+    fn sniff(&self, line: &str) -> bool {
+        tab_fields(line).len() == 2
+    }
+
+    fn parse(&self, reader: Box<dyn BufRead>) -> io::Result<Clusters> {
+        let clusters = group_tsv(reader, false, |fields| Some((fields.get(1)?, fields.first()?)))?;
+        Ok(Box::new(clusters.into_iter().map(Ok)))
+    }
+}
+
+/// starcode `centroid<TAB>count<TAB>members`, recognised by its numeric count.
+pub struct Starcode;
+
+impl ClusterFormat for Starcode {
+    fn name(&self) -> &str {
+        "starcode"
+    }
+
+    fn sniff(&self, line: &str) -> bool {
+        matches!(tab_fields(line).as_slice(), [_, count, _] if is_number(count))
+    }
+
+    fn parse(&self, reader: Box<dyn BufRead>) -> io::Result<Clusters> {
+        Ok(Box::new(read_starcode(reader)))
+    }
+}
+
+/// JSON or JSON Lines clusters, recognised by the opening `[` or `{`.
+pub struct Json;
+
+impl ClusterFormat for Json {
+    fn name(&self) -> &str {
+        "json"
+    }
+
+    fn sniff(&self, line: &str) -> bool {
+        line.starts_with('[') || line.starts_with('{')
+    }
+
+    fn parse(&self, reader: Box<dyn BufRead>) -> io::Result<Clusters> {
+        read_json(reader)
+    }
+}
+
+/// Read a VSEARCH/USEARCH `.uc` file. Every `S` (centroid) record opens a
+/// cluster and `H` (hit) records join it by cluster number, with their
+/// percent identity and strand relative to the centroid; `C` summary and
+/// `N` no-hit records are ignored. Hits need not follow their centroid, so the
+/// whole file is read before clusters are returned in cluster-number order.
+fn read_uc<R: BufRead>(reader: R) -> io::Result<Vec<Cluster>> {
+    let mut clusters: BTreeMap<u64, Cluster> = BTreeMap::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 9 {
+            warn!("Malformed line: {}", line);
+            continue;
+        }
+        let (record_type, label) = (fields[0], fields[8]);
+        if record_type != "S" && record_type != "H" {
+            continue;
+        }
+        let number: u64 = fields[1].parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Line {}: invalid cluster number {:?}", i + 1, fields[1]),
+            )
+        })?;
+        let cluster = clusters
+            .entry(number)
+            .or_insert_with(|| Cluster { id: number.to_string(), members: Vec::new() });
+        // The centroid leads its cluster even if hits were listed first
+        if record_type == "S" {
+            cluster.members.insert(0, Member::representative(label));
+        } else {
+            let identity = fields[3].parse().ok();
+            let strand = match fields[4] {
+                "+" => Some(Strand::Forward),
+                "-" => Some(Strand::Reverse),
+                _ => None,
+            };
+            cluster.members.push(Member { identity, strand, ..Member::new(label) });
+        }
+    }
+    Ok(clusters.into_values().collect())
+}
+
+/// Stream the clusters of a swarm output file, one per non-empty line, with
+/// the seed amplicon first as representative. swarm does not name clusters,
+/// so they are numbered from 0 in file order.
+fn read_swarm<R: BufRead>(reader: R) -> impl Iterator<Item = io::Result<Cluster>> {
+    reader
+        .lines()
+        .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .enumerate()
+        .map(|(i, line)| {
+            let line = line?;
+            let mut ids = line.split_whitespace();
+            let members = ids.next().map(Member::representative).into_iter().chain(ids.map(Member::new)).collect();
+            Ok(Cluster { id: i.to_string(), members })
+        })
+}
+
+/// Stream the clusters of a starcode output file, one per line, named by
+/// their centroid, which is also the representative. Lines without a
+/// member list (starcode run without `--print-clusters`) are reported and
+/// skipped.
+fn read_starcode<R: BufRead>(reader: R) -> impl Iterator<Item = io::Result<Cluster>> {
+    reader.lines().filter_map(|line| {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e)),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            return None;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            [centroid, _count, members, ..] => Some(Ok(Cluster {
+                id: centroid.to_string(),
+                members: members
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|m| !m.is_empty())
+                    .map(|m| if m == *centroid { Member::representative(m) } else { Member::new(m) })
+                    .collect(),
+            })),
+            _ => {
+                warn!("Malformed line: {}", line);
+                None
+            }
+        }
+    })
+}
+
+/// A cluster as written in JSON cluster files.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonCluster {
+    id: Option<String>,
+    members: Vec<String>,
+    representative: Option<String>,
+}
+
+impl JsonCluster {
+    /// Convert to a [`Cluster`], numbering it by position when it has no ID.
+    fn into_cluster(self, position: usize) -> Cluster {
+        let representative = self.representative;
+        let members = self
+            .members
+            .into_iter()
+            .map(|id| if representative.as_ref() == Some(&id) { Member::representative(id) } else { Member::new(id) })
+            .collect();
+        Cluster { id: self.id.unwrap_or_else(|| position.to_string()), members }
+    }
+}
+
+/// Parse a JSON cluster file. A document starting with `[` is read whole as
+/// an array of clusters; anything else is streamed as JSON Lines.
+fn read_json<R: BufRead + 'static>(mut reader: R) -> io::Result<Clusters> {
+    let is_array = loop {
+        let buf = reader.fill_buf()?;
+        match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(i) => break buf[i] == b'[',
+            None if buf.is_empty() => break false,
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+            }
+        }
+    };
+
+    if is_array {
+        let clusters: Vec<JsonCluster> = serde_json::from_reader(reader)?;
+        return Ok(Box::new(clusters.into_iter().enumerate().map(|(i, c)| Ok(c.into_cluster(i)))));
+    }
+    let lines = reader.lines().filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()));
+    Ok(Box::new(lines.enumerate().map(|(i, line)| {
+        let cluster: JsonCluster = serde_json::from_str(&line?).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("JSON cluster {}: {}", i + 1, e))
+        })?;
+        Ok(cluster.into_cluster(i))
+    })))
+}
+
+/// Group the lines of a tab-separated file into clusters. `columns` picks the
+/// cluster key and the member ID from the fields of a line, or returns `None`
+/// for a malformed line. Lines of one cluster need not be contiguous; clusters
+/// are returned in order of first appearance, named by their key. With
+/// `key_is_representative`, the key names the representative member.
+fn group_tsv<R, F>(reader: R, key_is_representative: bool, columns: F) -> io::Result<Vec<Cluster>>
+where
+    R: BufRead,
+    F: for<'a> Fn(&[&'a str]) -> Option<(&'a str, &'a str)>,
+{
+    let mut clusters: Vec<Cluster> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+        let Some((key, member)) = columns(&fields) else {
+            warn!("Malformed line: {}", line);
+            continue;
+        };
+        let position = *positions.entry(key.to_string()).or_insert_with(|| {
+            clusters.push(Cluster { id: key.to_string(), members: Vec::new() });
+            clusters.len() - 1
+        });
+        let member =
+            if key_is_representative && member == key { Member::representative(member) } else { Member::new(member) };
+        clusters[position].members.push(member);
+    }
+    Ok(clusters)
+}
@@ -0,0 +1,504 @@
+//! Consensus sequences built from the members of a cluster, written instead
+//! of any one member when no single read should stand for the cluster.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use bio::alignment::pairwise::{self, Scoring};
+use bio::alignment::poa;
+use bio::alignment::distance::levenshtein;
+use bio::alignment::AlignmentOperation;
+use bio::io::fastq;
+use clap::ValueEnum;
+use crate::record::PairedRecord;
+use crate::select::{iupac_bases, iupac_code, PHRED33};
+
+/// How the members of a cluster are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ConsensusMethod {
+    /// Per-position majority of the members of the most common length.
+    #[default]
+    Majority,
+    /// Heaviest path through a partial-order alignment of all members, for
+    /// long reads and clusters with indels.
+    Poa,
+    /// Partial-order consensus of the members with every homopolymer run
+    /// collapsed to one base, so the run-length errors typical of nanopore
+    /// reads do not disturb the alignment; each run is then re-expanded to
+    /// the median length the members give it.
+    Homopolymer,
+}
+
+/// Builds the consensus of a cluster mate by mate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Consensus {
+    pub method: ConsensusMethod,
+    /// ASCII offset of the member qualities, also used for the output.
+    pub phred_offset: u8,
+    /// Rounds of polishing: the members are aligned back to the draft and
+    /// every position, deletion and insertion re-voted.
+    pub rounds: usize,
+    /// Weigh every base's vote by the probability that it is correct, from
+    /// its quality, rather than giving each member one vote.
+    pub weighted: bool,
+    /// Write the IUPAC code of all bases carried by at least this percentage
+    /// of the members at a position, when there are several, instead of the
+    /// winner alone.
+    pub iupac: Option<f64>,
+}
+
+impl Default for Consensus {
+    fn default() -> Self {
+        Consensus { method: ConsensusMethod::default(), phred_offset: PHRED33, rounds: 0, weighted: false, iupac: None }
+    }
+}
+
+/// Highest phred quality given to a consensus base.
+pub const MAX_CONSENSUS_QUALITY: u8 = 60;
+
+/// Alignment scores, as used by spoa: a gap of k bases costs
+/// `GAP_OPEN + k * GAP_EXTEND`.
+const MATCH: i32 = 5;
+const MISMATCH: i32 = -4;
+const GAP_OPEN: i32 = -8;
+const GAP_EXTEND: i32 = -6;
+
+/// Pileup symbol of a member base deleted relative to the consensus.
+const GAP: u8 = b'-';
+
+/// The base (or [`GAP`]) and quality of every member at one consensus
+/// position.
+type Column = Vec<(u8, u8)>;
+
+/// Bases a read has in addition to the draft, with the draft position they
+/// are inserted before.
+type Insertion = (usize, Vec<u8>);
+
+/// The members of a cluster aligned to one mate of its consensus or of
+/// another read standing for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pileup {
+    /// Read IDs of the members, in row order.
+    pub ids: Vec<String>,
+    /// At every consensus position, the base of each member (`-` where it
+    /// has a deletion) and its quality. Bases a member has in addition to
+    /// the consensus are not represented.
+    pub columns: Vec<Vec<(u8, u8)>>,
+}
+
+impl Pileup {
+    /// The aligned bases of member `i`.
+    pub fn row(&self, i: usize) -> Vec<u8> {
+        self.columns.iter().map(|column| column[i].0).collect()
+    }
+
+    /// Write the alignment as FASTA, the consensus `seq` named `id` first and
+    /// then every member.
+    pub fn write_fasta(&self, id: &str, seq: &[u8], out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, ">{}", id)?;
+        out.write_all(seq)?;
+        writeln!(out)?;
+        for (i, member) in self.ids.iter().enumerate() {
+            writeln!(out, ">{}", member)?;
+            out.write_all(&self.row(i))?;
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    /// The alternatives to `reference`, the sequence the members were aligned
+    /// to, carried by at least `min_frequency` percent of the members, in
+    /// order of position and then of first appearance.
+    pub fn variants(&self, reference: &[u8], min_frequency: f64) -> Vec<Variant> {
+        let mut variants = Vec::new();
+        for (i, (column, &base)) in self.columns.iter().zip(reference).enumerate() {
+            let base = base.to_ascii_uppercase();
+            let mut counts: Vec<(u8, usize)> = Vec::new();
+            for &(b, _) in column.iter().filter(|(b, _)| *b != base) {
+                match counts.iter_mut().find(|(other, _)| *other == b) {
+                    Some((_, count)) => *count += 1,
+                    None => counts.push((b, 1)),
+                }
+            }
+            for (alternative, count) in counts {
+                let variant = Variant { position: i + 1, reference: base, alternative, count, depth: column.len() };
+                if variant.frequency() >= min_frequency {
+                    variants.push(variant);
+                }
+            }
+        }
+        variants
+    }
+}
+
+/// A base or deletion (`-`) that members have at a position instead of the
+/// one they were aligned to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    /// 1-based position in the aligned-to sequence.
+    pub position: usize,
+    pub reference: u8,
+    pub alternative: u8,
+    /// Number of members with the alternative.
+    pub count: usize,
+    /// Number of members aligned.
+    pub depth: usize,
+}
+
+impl Variant {
+    /// Percentage of the members with the alternative.
+    pub fn frequency(&self) -> f64 {
+        100.0 * self.count as f64 / self.depth as f64
+    }
+}
+
+/// The members aligned to every mate of `reference`, such as a cluster
+/// representative, leaving out members without that mate.
+pub fn align_to(reference: &PairedRecord, members: &[&PairedRecord]) -> Vec<Pileup> {
+    reference
+        .mates()
+        .enumerate()
+        .map(|(n, mate)| {
+            let reads: Vec<&fastq::Record> = members.iter().filter_map(|pair| pair.mates().nth(n)).collect();
+            let ids = reads.iter().map(|r| r.id().to_string()).collect();
+            Pileup { ids, columns: pileup(&mate.seq().to_ascii_uppercase(), &reads) }
+        })
+        .collect()
+}
+
+/// A consensus with the pileup of each of its mates.
+#[derive(Debug, Clone)]
+pub struct ConsensusRead {
+    pub pair: PairedRecord,
+    /// One per mate, R1 first.
+    pub pileups: Vec<Pileup>,
+}
+
+/// Percent identity of a member to the consensus over the mates both have:
+/// 100 less the edit distance as a percentage of the longer sequence, summed
+/// over the mates. Case is ignored.
+pub fn identity(member: &PairedRecord, consensus: &PairedRecord) -> f64 {
+    let (mut distance, mut length) = (0, 0);
+    for (a, b) in member.mates().zip(consensus.mates()) {
+        distance += levenshtein(&a.seq().to_ascii_uppercase(), &b.seq().to_ascii_uppercase()) as usize;
+        length += a.seq().len().max(b.seq().len());
+    }
+    if length == 0 { 100.0 } else { 100.0 * (1.0 - distance as f64 / length as f64) }
+}
+
+fn score_match(a: u8, b: u8) -> i32 {
+    if a == b { MATCH } else { MISMATCH }
+}
+
+impl Consensus {
+    /// The consensus of a non-empty cluster, mate by mate, named `id`. The
+    /// majority stacks only the members sharing the most common mate lengths,
+    /// position by position; the rest, typically truncated or indel-carrying
+    /// reads, are left out. Either method leaves out members without the
+    /// mates most members have.
+    pub fn build(&self, id: &str, members: &[&PairedRecord]) -> ConsensusRead {
+        let shape = |pair: &PairedRecord| -> Vec<usize> {
+            match self.method {
+                ConsensusMethod::Majority => pair.mates().map(|r| r.seq().len()).collect(),
+                ConsensusMethod::Poa | ConsensusMethod::Homopolymer => vec![pair.mates().count()],
+            }
+        };
+        let mut counts: HashMap<Vec<usize>, usize> = HashMap::new();
+        for pair in members {
+            *counts.entry(shape(pair)).or_insert(0) += 1;
+        }
+        // Equally common shapes go to the one seen first
+        let most_common = members
+            .iter()
+            .map(|pair| shape(pair))
+            .fold(None, |best: Option<Vec<usize>>, candidate| match best {
+                Some(best) if counts[&best] >= counts[&candidate] => Some(best),
+                _ => Some(candidate),
+            })
+            .expect("consensus of an empty cluster");
+        let stacked: Vec<&PairedRecord> = members.iter().copied().filter(|pair| shape(pair) == most_common).collect();
+
+        let mut pileups = Vec::new();
+        let mut mate = |mates: Vec<&fastq::Record>| {
+            let (seq, qual, columns) = self.mate_consensus(&mates);
+            pileups.push(Pileup { ids: mates.iter().map(|r| r.id().to_string()).collect(), columns });
+            fastq::Record::with_attrs(id, None, &seq, &qual)
+        };
+        let r1 = mate(stacked.iter().map(|pair| &pair.r1).collect());
+        let pair = match stacked[0].r2 {
+            Some(_) => PairedRecord::paired(r1, mate(stacked.iter().filter_map(|pair| pair.r2.as_ref()).collect())),
+            None => PairedRecord::single(r1),
+        };
+        ConsensusRead { pair, pileups }
+    }
+
+    /// Consensus sequence, qualities and pileup columns of one mate. Reads
+    /// without qualities give a consensus without qualities.
+    fn mate_consensus(&self, reads: &[&fastq::Record]) -> (Vec<u8>, Vec<u8>, Vec<Column>) {
+        let (seq, stacked) = match self.method {
+            ConsensusMethod::Majority => {
+                let columns = stack(reads);
+                (self.polished(columns.iter().map(|column| self.vote(column)).collect(), reads), Some(columns))
+            }
+            ConsensusMethod::Poa => (self.polished(poa(reads), reads), None),
+            ConsensusMethod::Homopolymer => (self.homopolymer(reads), None),
+        };
+        let columns = match stacked {
+            Some(columns) if self.rounds == 0 => columns,
+            _ => pileup(&seq, reads),
+        };
+        let seq = match self.iupac {
+            Some(min_frequency) => {
+                seq.iter().zip(&columns).map(|(&base, column)| ambiguous(base, column, min_frequency)).collect()
+            }
+            None => seq,
+        };
+        let qual = if reads.iter().all(|r| !r.qual().is_empty()) {
+            columns.iter().zip(&seq).map(|(column, &base)| self.quality(column, base)).collect()
+        } else {
+            Vec::new()
+        };
+        (seq, qual, columns)
+    }
+
+    /// `draft` after up to `rounds` rounds of polishing against `reads`,
+    /// stopping once a round changes nothing.
+    fn polished(&self, mut draft: Vec<u8>, reads: &[&fastq::Record]) -> Vec<u8> {
+        for _ in 0..self.rounds {
+            let polished = self.polish(&draft, reads);
+            if polished == draft {
+                break;
+            }
+            draft = polished;
+        }
+        draft
+    }
+
+    /// Consensus of the run-length compressed reads, polished in compressed
+    /// form, with every run expanded to the median length of the matching
+    /// runs aligned to it (the longer middle one for an even count).
+    fn homopolymer(&self, reads: &[&fastq::Record]) -> Vec<u8> {
+        let compressed: Vec<fastq::Record> = reads.iter().map(|read| compress(read)).collect();
+        let compressed: Vec<&fastq::Record> = compressed.iter().collect();
+        // The run lengths standing in for the qualities must not weigh votes
+        let unweighted = Consensus { weighted: false, ..self.clone() };
+        let draft = unweighted.polished(poa(&compressed), &compressed);
+        let mut seq = Vec::new();
+        for (&base, column) in draft.iter().zip(pileup(&draft, &compressed)) {
+            let mut lengths: Vec<u8> = column.iter().filter(|(b, _)| *b == base).map(|&(_, len)| len).collect();
+            lengths.sort_unstable();
+            let length = lengths.get(lengths.len() / 2).copied().unwrap_or(1);
+            seq.extend(std::iter::repeat_n(base, length as usize));
+        }
+        seq
+    }
+
+    /// The winning base (or [`GAP`]) of a column: by [`vote`], or with
+    /// `weighted` by the summed probabilities that the bases are correct. A
+    /// deletion has no quality and weighs as much as the mean base of its
+    /// column. Columns without qualities fall back to [`vote`], and equal
+    /// weights go to the base seen first.
+    fn vote(&self, column: &[(u8, u8)]) -> u8 {
+        if !self.weighted || column.iter().all(|&(_, q)| q == 0) {
+            return vote(column);
+        }
+        let weight = |q: u8| 1.0 - 10f64.powf(-(q.saturating_sub(self.phred_offset) as f64) / 10.0);
+        let bases: Vec<f64> = column.iter().filter(|(b, _)| *b != GAP).map(|&(_, q)| weight(q)).collect();
+        let deletion = if bases.is_empty() { 1.0 } else { bases.iter().sum::<f64>() / bases.len() as f64 };
+        // (base, summed weight) in order of first appearance
+        let mut votes: Vec<(u8, f64)> = Vec::new();
+        for &(base, q) in column {
+            let w = if base == GAP { deletion } else { weight(q) };
+            match votes.iter_mut().find(|(b, _)| *b == base) {
+                Some((_, total)) => *total += w,
+                None => votes.push((base, w)),
+            }
+        }
+        votes.iter().fold(votes[0], |best, &vote| if vote.1 > best.1 { vote } else { best }).0
+    }
+
+    /// One round of polishing `draft`: every read is aligned to it, a position
+    /// whose majority is a deletion is dropped, and bases inserted after a
+    /// position (or before the first) by more than half the reads are added,
+    /// the most common insertion winning.
+    fn polish(&self, draft: &[u8], reads: &[&fastq::Record]) -> Vec<u8> {
+        let mut columns = vec![Vec::with_capacity(reads.len()); draft.len()];
+        // Per slot, the insertions of the reads having one there; slot `j` lies
+        // before draft position `j`
+        let mut slots: Vec<Vec<Vec<u8>>> = vec![Vec::new(); draft.len() + 1];
+        let mut aligner = pairwise::Aligner::new(GAP_OPEN, GAP_EXTEND, score_match);
+        for read in reads {
+            let (bases, insertions) = align(&mut aligner, read, draft);
+            for (column, base) in columns.iter_mut().zip(bases) {
+                column.push(base);
+            }
+            for (slot, inserted) in insertions {
+                slots[slot].push(inserted);
+            }
+        }
+        let mut polished = Vec::with_capacity(draft.len());
+        for (j, slot) in slots.iter().enumerate() {
+            if slot.len() * 2 > reads.len() {
+                polished.extend(most_common(slot));
+            }
+            if let Some(column) = columns.get(j) {
+                let base = self.vote(column);
+                if base != GAP {
+                    polished.push(base);
+                }
+            }
+        }
+        polished
+    }
+
+    /// Quality of consensus `base` from the evidence in its column: the phred
+    /// qualities of the members agreeing with it summed, less those of the
+    /// members calling another base, between 0 and [`MAX_CONSENSUS_QUALITY`].
+    /// Members with a deletion there count neither way. With `iupac`, the
+    /// members with any of the bases of an ambiguity code agree with it.
+    fn quality(&self, column: &[(u8, u8)], base: u8) -> u8 {
+        let code = iupac_bases(base);
+        let ambiguous = self.iupac.is_some() && code.count_ones() > 1;
+        let evidence: i64 = column
+            .iter()
+            .map(|&(b, q)| {
+                let q = q.saturating_sub(self.phred_offset) as i64;
+                if b == base || (ambiguous && iupac_bases(b) != 0 && iupac_bases(b) & !code == 0) {
+                    q
+                } else if b == GAP {
+                    0
+                } else {
+                    -q
+                }
+            })
+            .sum();
+        evidence.clamp(0, MAX_CONSENSUS_QUALITY as i64) as u8 + self.phred_offset
+    }
+}
+
+/// The columns of reads of one length stacked position by position.
+fn stack(reads: &[&fastq::Record]) -> Vec<Column> {
+    (0..reads[0].seq().len())
+        .map(|i| {
+            reads.iter().map(|r| (r.seq()[i].to_ascii_uppercase(), r.qual().get(i).copied().unwrap_or(0))).collect()
+        })
+        .collect()
+}
+
+/// The most common base of a column; equally common bases go to the one with
+/// the single highest quality, then to the one seen first.
+fn vote(column: &[(u8, u8)]) -> u8 {
+    // (base, copies, best quality) in order of first appearance
+    let mut votes: Vec<(u8, usize, u8)> = Vec::new();
+    for &(base, q) in column {
+        match votes.iter_mut().find(|(b, _, _)| *b == base) {
+            Some((_, copies, best)) => {
+                *copies += 1;
+                *best = (*best).max(q);
+            }
+            None => votes.push((base, 1, q)),
+        }
+    }
+    votes.iter().fold(votes[0], |best, &vote| if (vote.1, vote.2) > (best.1, best.2) { vote } else { best }).0
+}
+
+/// The IUPAC code of the bases (A, C, G or T) carried by at least
+/// `min_frequency` percent of the members in `column`, or `base`, its winner,
+/// when fewer than two are or the winner is a deletion.
+fn ambiguous(base: u8, column: &[(u8, u8)], min_frequency: f64) -> u8 {
+    if base == GAP {
+        return base;
+    }
+    let mut bases = 0;
+    for &(b, _) in column.iter().filter(|(b, _)| iupac_bases(*b).count_ones() == 1) {
+        let count = column.iter().filter(|(other, _)| *other == b).count();
+        if 100.0 * count as f64 / column.len() as f64 >= min_frequency {
+            bases |= iupac_bases(b);
+        }
+    }
+    if bases.count_ones() > 1 { iupac_code(bases) } else { base }
+}
+
+/// Consensus of reads of any length: the heaviest path through their
+/// partial-order alignment, added to the graph in the order given.
+fn poa(reads: &[&fastq::Record]) -> Vec<u8> {
+    let seqs: Vec<Vec<u8>> = reads.iter().map(|r| r.seq().to_ascii_uppercase()).collect();
+    let mut aligner = poa::Aligner::new(Scoring::new(GAP_OPEN, GAP_EXTEND, score_match), &seqs[0]);
+    for seq in &seqs[1..] {
+        aligner.global(seq).add_to_graph();
+    }
+    aligner.consensus()
+}
+
+/// The columns of every position of `draft`, from a global alignment of each
+/// read to it; deleted bases have quality 0. Bases a read has in addition to
+/// the draft are left out.
+fn pileup(draft: &[u8], reads: &[&fastq::Record]) -> Vec<Column> {
+    let mut columns = vec![Vec::with_capacity(reads.len()); draft.len()];
+    let mut aligner = pairwise::Aligner::new(GAP_OPEN, GAP_EXTEND, score_match);
+    for read in reads {
+        let (bases, _) = align(&mut aligner, read, draft);
+        for (column, base) in columns.iter_mut().zip(bases) {
+            column.push(base);
+        }
+    }
+    columns
+}
+
+/// The base (or [`GAP`]) and quality of `read` at every position of `draft`,
+/// from a global alignment, and the bases it inserts with the slot before
+/// which they are inserted.
+fn align(
+    aligner: &mut pairwise::Aligner<impl Fn(u8, u8) -> i32>,
+    read: &fastq::Record,
+    draft: &[u8],
+) -> (Vec<(u8, u8)>, Vec<Insertion>) {
+    let seq = read.seq().to_ascii_uppercase();
+    let alignment = aligner.global(&seq, draft);
+    let mut bases = Vec::with_capacity(draft.len());
+    let mut insertions: Vec<Insertion> = Vec::new();
+    let mut i = 0;
+    for op in &alignment.operations {
+        match op {
+            AlignmentOperation::Match | AlignmentOperation::Subst => {
+                bases.push((seq[i], read.qual().get(i).copied().unwrap_or(0)));
+                i += 1;
+            }
+            AlignmentOperation::Del => bases.push((GAP, 0)),
+            AlignmentOperation::Ins => {
+                let slot = bases.len();
+                match insertions.last_mut() {
+                    Some((last, inserted)) if *last == slot => inserted.push(seq[i]),
+                    _ => insertions.push((slot, vec![seq[i]])),
+                }
+                i += 1;
+            }
+            // A global alignment clips neither sequence
+            AlignmentOperation::Xclip(n) => i += n,
+            AlignmentOperation::Yclip(n) => bases.extend(std::iter::repeat_n((GAP, 0), *n)),
+        }
+    }
+    (bases, insertions)
+}
+
+/// `read` with every homopolymer run collapsed to a single base, the run
+/// lengths (up to 255) taking the place of the qualities.
+fn compress(read: &fastq::Record) -> fastq::Record {
+    let (mut seq, mut lengths): (Vec<u8>, Vec<u8>) = (Vec::new(), Vec::new());
+    for base in read.seq().to_ascii_uppercase() {
+        match (seq.last(), lengths.last_mut()) {
+            (Some(&last), Some(length)) if last == base => *length = length.saturating_add(1),
+            _ => {
+                seq.push(base);
+                lengths.push(1);
+            }
+        }
+    }
+    fastq::Record::with_attrs(read.id(), None, &seq, &lengths)
+}
+
+/// The most common of a non-empty list of sequences, equally common ones
+/// going to the one seen first.
+fn most_common(seqs: &[Vec<u8>]) -> &[u8] {
+    let count = |seq: &Vec<u8>| seqs.iter().filter(|other| *other == seq).count();
+    seqs.iter().fold(&seqs[0], |best, seq| if count(seq) > count(best) { seq } else { best }).as_slice()
+}
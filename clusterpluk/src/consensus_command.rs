@@ -0,0 +1,193 @@
+use std::io::{BufReader, Write};
+use log::{info, warn};
+use clusterpluk::clusters::{merge_clusters, read_clusters, ClusterFileFormat};
+use clusterpluk::consensus::{Consensus, ConsensusMethod};
+use clusterpluk::ids::IdNormalization;
+use clusterpluk::index::Indexer;
+use clusterpluk::input::{open_input, open_lanes};
+use clusterpluk::output::{is_stdout, Codec, OutputCompression, OutputFormat};
+use clusterpluk::record::{PairedRecord, SeqFormat};
+use clusterpluk::select::{detect_phred_offset, quality_range, PHRED33};
+use crate::{
+    open_identity_stats, open_variant_table, open_writer, parse_percentage, report, write_identity_stats,
+    write_msa, write_variants, QUALITY_SAMPLE,
+};
+
+/// Write the consensus of every cluster, named `cluster<ID>`, without picking representatives
+#[derive(clap::Args, Clone)]
+pub struct ConsensusArgs {
+    /// Input reads, R1 for pairs ('-' for stdin). Repeat for multi-lane samples
+    #[arg(short, long, visible_alias = "r1", required = true)]
+    input: Vec<String>,
+
+    /// Input R2 reads, making the consensus paired. Repeat for multi-lane samples
+    #[arg(long, visible_alias = "r2")]
+    input2: Vec<String>,
+
+    /// Input cluster file ('-' for stdin). Repeat for data clustered in chunks
+    #[arg(short, long, required = true)]
+    cluster: Vec<String>,
+
+    /// Format of the --cluster file
+    #[arg(long, value_enum, default_value_t = ClusterFileFormat::Auto)]
+    cluster_format: ClusterFileFormat,
+
+    /// Output file, R1 for pairs ('-' for stdout); pairs are interleaved in it when --output2 is
+    /// omitted
+    #[arg(short, long)]
+    output: String,
+
+    /// Output R2 file
+    #[arg(long, requires = "input2")]
+    output2: Option<String>,
+
+    /// How the members of a cluster are combined
+    #[arg(long, value_enum, default_value_t = ConsensusMethod::Majority)]
+    method: ConsensusMethod,
+
+    /// Polish every consensus this many times against the realigned members
+    #[arg(long, default_value_t = 0)]
+    rounds: usize,
+
+    /// Weigh every base's vote by the probability that it is correct, from its quality
+    #[arg(long)]
+    weighted_vote: bool,
+
+    /// Write the IUPAC ambiguity code of all bases carried by at least this percentage of the
+    /// members at a position where there are several
+    #[arg(long, value_parser = parse_percentage)]
+    iupac: Option<f64>,
+
+    /// Skip clusters with fewer members found in the reads than this
+    #[arg(long, default_value_t = 1)]
+    min_size: usize,
+
+    /// Write the alignment of the members each consensus was called from to this directory
+    #[arg(long)]
+    write_msa: Option<String>,
+
+    /// Write a TSV of every cluster's member count and the mean and lowest percent identity of
+    /// its members to the consensus
+    #[arg(long)]
+    identity_stats: Option<String>,
+
+    /// Write a TSV of the positions where at least --variant-min-frequency percent of the
+    /// members disagree with the consensus
+    #[arg(long)]
+    variant_table: Option<String>,
+
+    /// Lowest percentage of the members sharing an alternative for --variant-table to list it
+    #[arg(long, default_value_t = 10.0, value_parser = parse_percentage)]
+    variant_min_frequency: f64,
+
+    /// Compress the output with this codec [default when given bare: gzip]. Outputs ending in
+    /// .gz, .bgz or .zst are compressed accordingly regardless
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "gzip")]
+    compress_output: Option<Codec>,
+
+    /// Compression level of the output: 0 (fastest) to 9 for gzip and bgzf, up to 22 for zstd
+    /// [default: 6 for gzip and bgzf, 3 for zstd]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=22))]
+    compression_level: Option<u32>,
+
+    /// Append the USEARCH-style abundance annotation ;size=N, N being the number of members
+    /// the cluster lists, to the consensus IDs
+    #[arg(long)]
+    size_annotation: bool,
+
+    /// Write FASTA even when the reads have qualities
+    #[arg(long)]
+    fasta: bool,
+
+    /// How read IDs are normalised before FASTQ records and cluster members are matched
+    #[arg(long, value_enum, default_value_t = IdNormalization::None)]
+    normalize_ids: IdNormalization,
+}
+
+/// Build and write the consensus of every cluster listed in the cluster files.
+pub fn run_consensus(args: &ConsensusArgs) {
+    let to_stdout = [Some(&args.output), args.output2.as_ref()].into_iter().flatten().filter(|p| is_stdout(p)).count();
+    if to_stdout > 1 {
+        eprintln!("Only one output file can be written to stdout");
+        std::process::exit(1);
+    }
+    let indexer = Indexer {
+        ids: args.normalize_ids,
+        on_duplicate: Default::default(),
+        unpaired: Default::default(),
+        ignore_truncation: false,
+    };
+    let open = |paths: &[String], what: &str| {
+        open_lanes(paths, None, None).unwrap_or_else(|e| panic!("Failed to open {} reads: {}", what, e))
+    };
+    info!("\nCreating index for: {}", args.input.iter().chain(&args.input2).cloned().collect::<Vec<_>>().join(", "));
+    let (format, index) = if args.input2.is_empty() {
+        let (format, records) = open(&args.input, "single-end");
+        (format, indexer.single(records))
+    } else {
+        let (format, r1_records) = open(&args.input, "R1");
+        let (_, r2_records) = open(&args.input2, "R2");
+        let (index, orphans) = indexer.paired(r1_records, r2_records);
+        if !orphans.is_empty() {
+            warn!("{} reads have no mate in the other mate file", orphans.len());
+        }
+        (format, index)
+    };
+
+    let sample = index.values().take(QUALITY_SAMPLE).flat_map(|pair| pair.mates()).map(|r| r.qual());
+    let consensus = Consensus {
+        method: args.method,
+        phred_offset: match format {
+            SeqFormat::Fastq => quality_range(sample).map_or(PHRED33, detect_phred_offset),
+            _ => PHRED33,
+        },
+        rounds: args.rounds,
+        weighted: args.weighted_vote,
+        iupac: args.iupac,
+    };
+    if let Some(dir) = &args.write_msa {
+        std::fs::create_dir_all(dir).unwrap_or_else(|e| panic!("Failed to create MSA directory {}: {}", dir, e));
+    }
+
+    let mut cluster_files = Vec::new();
+    for path in &args.cluster {
+        let reader = BufReader::new(open_input(path).expect("Could not open cluster file"));
+        cluster_files.push(
+            read_clusters(reader, args.cluster_format)
+                .unwrap_or_else(|e| panic!("Failed to read cluster file {}: {}", path, e)),
+        );
+    }
+    let clusters = if cluster_files.len() == 1 { cluster_files.remove(0) } else { merge_clusters(cluster_files) };
+    let output_format = if args.fasta { OutputFormat::Fasta } else { format.into() };
+    let compression = OutputCompression { codec: args.compress_output, level: args.compression_level };
+    let mut writer = open_writer(&args.output, args.output2.as_deref(), output_format, compression);
+    let mut identity_stats = args.identity_stats.as_deref().map(open_identity_stats);
+    let mut variant_table = args.variant_table.as_deref().map(open_variant_table);
+
+    let (mut cluster_count, mut written) = (0, 0);
+    for cluster in clusters {
+        let cluster = cluster.expect("Failed to read cluster file");
+        cluster_count += 1;
+        let members: Vec<&PairedRecord> =
+            cluster.members.iter().filter_map(|member| index.get(args.normalize_ids.normalize(&member.id))).collect();
+        if members.is_empty() || members.len() < args.min_size {
+            continue;
+        }
+        let name = format!("cluster{}", cluster.id);
+        let consensus = consensus.build(&name, &members);
+        if let Some(dir) = &args.write_msa {
+            write_msa(dir, &name, &consensus);
+        }
+        if let Some(out) = &mut identity_stats {
+            write_identity_stats(out, &cluster.id, &members, &consensus.pair);
+        }
+        if let Some(out) = &mut variant_table {
+            write_variants(out, &cluster.id, &consensus.pileups, &consensus.pair, args.variant_min_frequency);
+        }
+        let pair = if args.size_annotation { consensus.pair.with_size(cluster.members.len()) } else { consensus.pair };
+        writer.write_pair(&pair).expect("Failed to write output");
+        written += 1;
+    }
+    let summary = format!("Clusters processed: {}\nConsensus sequences written: {}\n", cluster_count, written);
+    report(to_stdout > 0).write_all(summary.as_bytes()).expect("Failed to print summary");
+}
@@ -0,0 +1,313 @@
+//! Arithmetic scoring expressions such as `mean_q - 0.01*n_count + 0.001*len`,
+//! evaluated per cluster member by `--select expression`.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Per-member values an expression can refer to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variable {
+    /// Length, R1 and R2 combined.
+    Len,
+    /// Mean phred quality.
+    MeanQ,
+    /// Expected number of errors, the sum of the per-base error probabilities.
+    Ee,
+    /// Number of `N` bases.
+    NCount,
+    /// Percent identity to the cluster representative; 100 when not recorded.
+    Identity,
+    /// Sequence complexity, from 0 for a homopolymer to 1.
+    Complexity,
+}
+
+impl Variable {
+    const ALL: [(&'static str, Variable); 6] = [
+        ("len", Variable::Len),
+        ("mean_q", Variable::MeanQ),
+        ("ee", Variable::Ee),
+        ("n_count", Variable::NCount),
+        ("identity", Variable::Identity),
+        ("complexity", Variable::Complexity),
+    ];
+
+    fn name(self) -> &'static str {
+        Variable::ALL.iter().find(|(_, v)| *v == self).unwrap().0
+    }
+}
+
+/// Values of the [`Variable`]s for one member.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Variables {
+    pub len: f64,
+    pub mean_q: f64,
+    pub ee: f64,
+    pub n_count: f64,
+    pub identity: f64,
+    pub complexity: f64,
+}
+
+impl Variables {
+    fn get(&self, variable: Variable) -> f64 {
+        match variable {
+            Variable::Len => self.len,
+            Variable::MeanQ => self.mean_q,
+            Variable::Ee => self.ee,
+            Variable::NCount => self.n_count,
+            Variable::Identity => self.identity,
+            Variable::Complexity => self.complexity,
+        }
+    }
+}
+
+/// Binary operators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+}
+
+/// A parsed scoring expression. Numbers, the variables above, `+ - * / ^`
+/// (`^` binding tightest and to the right), unary minus and parentheses are
+/// supported.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Var(Variable),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn eval(&self, vars: &Variables) -> f64 {
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Var(v) => vars.get(*v),
+            Expr::Neg(e) => -e.eval(vars),
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.eval(vars), b.eval(vars));
+                match op {
+                    Op::Add => a + b,
+                    Op::Sub => a - b,
+                    Op::Mul => a * b,
+                    Op::Div => a / b,
+                    Op::Pow => a.powf(b),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Number(n) => write!(f, "{}", n),
+            Expr::Var(v) => write!(f, "{}", v.name()),
+            Expr::Neg(e) => write!(f, "-{}", e),
+            Expr::Binary(op, a, b) => {
+                let op = match op {
+                    Op::Add => "+",
+                    Op::Sub => "-",
+                    Op::Mul => "*",
+                    Op::Div => "/",
+                    Op::Pow => "^",
+                };
+                write!(f, "({} {} {})", a, op, b)
+            }
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { input: s, pos: 0 };
+        let expr = parser.expr()?;
+        parser.skip_whitespace();
+        match parser.peek() {
+            None => Ok(expr),
+            Some(c) => Err(parser.error(&format!("unexpected {:?}", c))),
+        }
+    }
+}
+
+/// Recursive-descent parser over the expression grammar
+///
+/// ```text
+/// expr  = term (("+" | "-") term)*
+/// term  = unary (("*" | "/") unary)*
+/// unary = "-" unary | power
+/// power = atom ("^" unary)?
+/// atom  = number | variable | "(" expr ")"
+/// ```
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        format!("{} at position {} of {:?}", message, self.pos + 1, self.input)
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.input[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    /// Consume `c` if it is the next non-whitespace character.
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut expr = self.term()?;
+        loop {
+            let op = if self.eat('+') {
+                Op::Add
+            } else if self.eat('-') {
+                Op::Sub
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        loop {
+            let op = if self.eat('*') {
+                Op::Mul
+            } else if self.eat('/') {
+                Op::Div
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat('-') {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        let base = self.atom()?;
+        if self.eat('^') {
+            return Ok(Expr::Binary(Op::Pow, Box::new(base), Box::new(self.unary()?)));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<Expr, String> {
+        if self.eat('(') {
+            let expr = self.expr()?;
+            if !self.eat(')') {
+                return Err(self.error("expected ')'"));
+            }
+            return Ok(expr);
+        }
+        self.skip_whitespace();
+        let rest = &self.input[self.pos..];
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let len = rest.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(rest.len());
+                let number =
+                    rest[..len].parse().map_err(|_| self.error(&format!("invalid number {:?}", &rest[..len])))?;
+                self.pos += len;
+                Ok(Expr::Number(number))
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+                let name = &rest[..len];
+                let names: Vec<&str> = Variable::ALL.iter().map(|(name, _)| *name).collect();
+                let variable = Variable::ALL.iter().find(|(n, _)| *n == name).map(|(_, v)| *v).ok_or_else(|| {
+                    self.error(&format!("unknown variable {:?}, expected one of {}", name, names.join(", ")))
+                })?;
+                self.pos += len;
+                Ok(Expr::Var(variable))
+            }
+            Some(c) => Err(self.error(&format!("unexpected {:?}", c))),
+            None => Err(self.error("unexpected end of expression")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: &str) -> f64 {
+        eval_with(expr, &Variables::default())
+    }
+
+    fn eval_with(expr: &str, vars: &Variables) -> f64 {
+        expr.parse::<Expr>().unwrap_or_else(|e| panic!("failed to parse {:?}: {}", expr, e)).eval(vars)
+    }
+
+    fn error(expr: &str) -> String {
+        expr.parse::<Expr>().expect_err("parsed an invalid expression")
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), 7.0);
+        assert_eq!(eval("(1 + 2) * 3"), 9.0);
+        assert_eq!(eval("8 - 4 - 2"), 2.0);
+        assert_eq!(eval("8 / 4 / 2"), 1.0);
+        assert_eq!(eval("2 * 3 ^ 2"), 18.0);
+        assert_eq!("1 + 2 * 3".parse::<Expr>().unwrap().to_string(), "(1 + (2 * 3))");
+    }
+
+    #[test]
+    fn power_is_right_associative() {
+        assert_eq!(eval("2 ^ 3 ^ 2"), 512.0);
+        assert_eq!("2^3^2".parse::<Expr>().unwrap().to_string(), "(2 ^ (3 ^ 2))");
+    }
+
+    #[test]
+    fn unary_minus() {
+        assert_eq!(eval("-2 ^ 2"), -4.0);
+        assert_eq!(eval("2 * -3"), -6.0);
+        assert_eq!(eval("--1"), 1.0);
+        assert_eq!(eval("1 - -1"), 2.0);
+        assert_eq!(eval("2 ^ -1"), 0.5);
+    }
+
+    #[test]
+    fn variables() {
+        let vars = Variables { len: 150.0, mean_q: 35.0, n_count: 2.0, ..Default::default() };
+        assert_eq!(eval_with("mean_q - 0.5*n_count + 0.1*len", &vars), 49.0);
+        assert_eq!(eval_with("len", &vars), 150.0);
+    }
+
+    #[test]
+    fn unknown_variable() {
+        let e = error("mean_q + foo");
+        assert!(e.starts_with("unknown variable \"foo\", expected one of len, mean_q, ee"), "{}", e);
+        assert!(e.ends_with("at position 10 of \"mean_q + foo\""), "{}", e);
+    }
+
+    #[test]
+    fn error_positions() {
+        assert_eq!(error("1 + * 2"), "unexpected '*' at position 5 of \"1 + * 2\"");
+        assert_eq!(error("(1 + 2"), "expected ')' at position 7 of \"(1 + 2\"");
+        assert_eq!(error("1 2"), "unexpected '2' at position 3 of \"1 2\"");
+        assert_eq!(error(""), "unexpected end of expression at position 1 of \"\"");
+        assert_eq!(error("1.2.3"), "invalid number \"1.2.3\" at position 1 of \"1.2.3\"");
+    }
+}
@@ -0,0 +1,106 @@
+use clap::ValueEnum;
+
+/// How read IDs are normalised before FASTQ records and cluster members are
+/// matched up. The same rule is applied to every input, so `read123/1` in a
+/// cluster file finds `@read123 1:N:0:ACGT` in the FASTQ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum IdNormalization {
+    /// Match IDs exactly as written.
+    #[default]
+    None,
+    /// Drop everything after the first whitespace and a trailing `/1` or `/2`
+    /// mate suffix.
+    Mate,
+    /// Reduce Illumina Casava 1.8+ read names to their
+    /// `instrument:run:flowcell:lane:tile:x:y` part, ignoring comments and any
+    /// suffix glued onto the y coordinate. Other names fall back to `mate`.
+    Casava,
+}
+
+impl IdNormalization {
+    pub fn normalize(self, id: &str) -> &str {
+        match self {
+            IdNormalization::None => id,
+            IdNormalization::Mate => strip_mate_suffix(first_word(id)),
+            IdNormalization::Casava => {
+                let id = first_word(id);
+                casava_read_name(id).unwrap_or_else(|| strip_mate_suffix(id))
+            }
+        }
+    }
+}
+
+/// The sample name a pooled read ID starts with: everything before the first
+/// `delimiter`, or `None` without one.
+pub fn sample_prefix(id: &str, delimiter: char) -> Option<&str> {
+    id.split_once(delimiter).map(|(sample, _)| sample).filter(|sample| !sample.is_empty())
+}
+
+fn first_word(id: &str) -> &str {
+    id.split_whitespace().next().unwrap_or("")
+}
+
+fn strip_mate_suffix(id: &str) -> &str {
+    id.strip_suffix("/1").or_else(|| id.strip_suffix("/2")).unwrap_or(id)
+}
+
+/// Number of colon-separated fields in a Casava 1.8+ read name.
+const CASAVA_FIELDS: usize = 7;
+
+/// The `instrument:run:flowcell:lane:tile:x:y` prefix of `id`, or `None` when
+/// `id` does not look like a Casava 1.8+ read name. The y coordinate ends at
+/// its last digit, so `..:197393/1` and `..:197393_1:N:0:ACGT` (as left behind
+/// by tools that replace spaces in FASTA headers) reduce to the same key.
+fn casava_read_name(id: &str) -> Option<&str> {
+    let mut end = 0;
+    for (i, field) in id.split(':').take(CASAVA_FIELDS).enumerate() {
+        let numeric_len = field.bytes().take_while(u8::is_ascii_digit).count();
+        match i {
+            0 | 2 if field.is_empty() => return None,
+            1 | 3..=5 if numeric_len != field.len() || field.is_empty() => return None,
+            6 if numeric_len == 0 => return None,
+            6 => return Some(&id[..end + numeric_len]),
+            _ => {}
+        }
+        end += field.len() + 1;
+    }
+    None
+}
+
+/// Where reads carry their unique molecular identifier (UMI).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UmiFormat {
+    /// Appended to the read name after an underscore, as by `umi_tools extract`:
+    /// `@read1_ACGTACGT`
+    UmiTools,
+    /// The eighth colon-separated field of an Illumina read name, as written by bcl-convert:
+    /// `@instrument:run:flowcell:1:1101:1000:2000:ACGTACGT`
+    Illumina,
+    /// A `UMI=ACGTACGT` or SAM-style `RX:Z:ACGTACGT` field in the read name or comment
+    Tag,
+}
+
+impl UmiFormat {
+    /// The UMI of a read with the given name and comment, if it carries one.
+    pub fn umi<'r>(self, id: &'r str, desc: Option<&'r str>) -> Option<&'r str> {
+        let name = strip_mate_suffix(first_word(id));
+        let umi = match self {
+            UmiFormat::UmiTools => name.rsplit_once('_').map(|(_, umi)| umi),
+            UmiFormat::Illumina => name.split(':').nth(CASAVA_FIELDS),
+            UmiFormat::Tag => [Some(id), desc].into_iter().flatten().find_map(tagged_umi),
+        }?;
+        let valid = !umi.is_empty() && umi.bytes().all(|b| b.is_ascii_alphabetic() || b == b'+' || b == b'-');
+        valid.then_some(umi)
+    }
+}
+
+/// The value of the first `UMI=` or `RX:Z:` field in `text`, which runs up to
+/// the next whitespace, `:` or `;`.
+fn tagged_umi(text: &str) -> Option<&str> {
+    ["UMI=", "RX:Z:"].iter().find_map(|tag| {
+        let start = text.find(tag)? + tag.len();
+        let rest = &text[start..];
+        let end = rest.find(|c: char| c.is_whitespace() || c == ':' || c == ';').unwrap_or(rest.len());
+        Some(&rest[..end])
+    })
+}
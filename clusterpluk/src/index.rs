@@ -0,0 +1,283 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+use std::process;
+use bio::io::fastq;
+use clap::ValueEnum;
+use log::{error, warn};
+use crate::alignment::Alignment;
+use crate::ids::IdNormalization;
+use crate::record::PairedRecord;
+
+/// Cluster members available for selection, keyed by normalised read ID.
+pub type ReadIndex = HashMap<String, PairedRecord>;
+
+/// What to do when a read ID occurs more than once in the same input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum DuplicatePolicy {
+    /// Abort with an error naming the duplicated ID.
+    Error,
+    /// Keep the first record with the ID.
+    First,
+    /// Keep the last record with the ID.
+    Last,
+    /// Keep the last record with the ID and log a warning.
+    #[default]
+    Warn,
+}
+
+/// What to do with reads whose mate is missing from the other mate file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum UnpairedPolicy {
+    /// Leave them out of the clusters.
+    #[default]
+    Drop,
+    /// Keep R1 reads without an R2 mate as single-end members; R2 reads
+    /// without an R1 mate are still dropped.
+    R1Only,
+    /// Abort with an error naming the first unpaired read.
+    Error,
+}
+
+/// Which mate of a template a record is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Segment {
+    First,
+    Last,
+}
+
+/// The segment an aligned record is flagged as, if it carries flags.
+fn segment(record: &fastq::Record) -> Option<Segment> {
+    let flags = Alignment::from_description(record.desc()?)?.flags;
+    match (flags.is_first_segment(), flags.is_last_segment()) {
+        (true, false) => Some(Segment::First),
+        (false, true) => Some(Segment::Last),
+        _ => None,
+    }
+}
+
+/// Builds read indices, keying every record by its normalised ID.
+#[derive(Debug, Clone, Copy)]
+pub struct Indexer {
+    pub ids: IdNormalization,
+    pub on_duplicate: DuplicatePolicy,
+    pub unpaired: UnpairedPolicy,
+    /// Keep the records read before a truncated or corrupt stretch of input
+    /// instead of aborting.
+    pub ignore_truncation: bool,
+}
+
+impl Indexer {
+    fn key(&self, record: &fastq::Record) -> String {
+        self.ids.normalize(record.id()).to_string()
+    }
+
+    /// The records of `records` up to the first read error. The error is fatal
+    /// unless `ignore_truncation` is set, in which case the stream just ends.
+    fn read<'a>(
+        &self,
+        records: impl Iterator<Item = io::Result<fastq::Record>> + 'a,
+        what: &'a str,
+    ) -> impl Iterator<Item = fastq::Record> + 'a {
+        let ignore_truncation = self.ignore_truncation;
+        records.map_while(move |result| match result {
+            Ok(record) => Some(record),
+            Err(e) if ignore_truncation => {
+                warn!("Error reading {} reads: {}; continuing with the records read so far", what, e);
+                None
+            }
+            Err(e) => {
+                error!("Error reading {} reads: {}", what, e);
+                error!("Pass --ignore-truncation to continue with the records read so far");
+                process::exit(1);
+            }
+        })
+    }
+
+    /// Whether a record whose ID (`id`) was already indexed from `what` should
+    /// replace the earlier one.
+    fn replace_duplicate(&self, id: &str, what: &str) -> bool {
+        match self.on_duplicate {
+            DuplicatePolicy::Error => {
+                error!("Duplicate read ID {} in {} reads", id, what);
+                process::exit(1);
+            }
+            DuplicatePolicy::First => false,
+            DuplicatePolicy::Last => true,
+            DuplicatePolicy::Warn => {
+                warn!("Duplicate read ID {} in {} reads; keeping the last record", id, what);
+                true
+            }
+        }
+    }
+
+    /// Insert `value` under `key`, resolving a duplicate key per `on_duplicate`.
+    fn insert<V>(&self, index: &mut HashMap<String, V>, key: String, value: V, what: &str) {
+        match index.entry(key) {
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+            }
+            Entry::Occupied(mut entry) => {
+                if self.replace_duplicate(entry.key(), what) {
+                    entry.insert(value);
+                }
+            }
+        }
+    }
+
+    /// Index the plain records of a stream, such as I1/I2 index reads.
+    pub fn records(
+        &self,
+        records: impl Iterator<Item = io::Result<fastq::Record>>,
+        what: &str,
+    ) -> HashMap<String, fastq::Record> {
+        let mut index = HashMap::new();
+        for record in self.read(records, what) {
+            self.insert(&mut index, self.key(&record), record, what);
+        }
+        index
+    }
+
+    /// Index every record of a single-end stream.
+    pub fn single(&self, records: impl Iterator<Item = io::Result<fastq::Record>>) -> ReadIndex {
+        let mut index = HashMap::new();
+        for record in self.read(records, "input") {
+            self.insert(&mut index, self.key(&record), PairedRecord::single(record), "input");
+        }
+        index
+    }
+
+    /// Index R1 and R2 streams, pairing mates by ID. Reads missing their mate
+    /// are handled per `unpaired` and also returned, so they can be audited.
+    pub fn paired(
+        &self,
+        r1_records: impl Iterator<Item = io::Result<fastq::Record>>,
+        r2_records: impl Iterator<Item = io::Result<fastq::Record>>,
+    ) -> (ReadIndex, Vec<fastq::Record>) {
+        let mut index = HashMap::new();
+        for record in self.read(r1_records, "R1") {
+            self.insert(&mut index, self.key(&record), PairedRecord::single(record), "R1");
+        }
+        let mut r2_orphans = Vec::new();
+        for record in self.read(r2_records, "R2") {
+            let id = self.ids.normalize(record.id());
+            match index.get_mut(id) {
+                Some(pair) if pair.r2.is_none() || self.replace_duplicate(id, "R2") => pair.r2 = Some(record),
+                Some(_) => {}
+                None => r2_orphans.push(record),
+            }
+        }
+        // R1 orphans are sorted by ID, as the index forgets the input order
+        let mut orphans: Vec<_> =
+            index.values().filter(|pair| pair.r2.is_none()).map(|pair| pair.r1.clone()).collect();
+        orphans.sort_by(|a, b| a.id().cmp(b.id()));
+        orphans.extend(r2_orphans);
+        if self.unpaired == UnpairedPolicy::Error && !orphans.is_empty() {
+            error!(
+                "{} reads have no mate in the other mate file, e.g. {}",
+                orphans.len(),
+                orphans[0].id()
+            );
+            process::exit(1);
+        }
+        if self.unpaired == UnpairedPolicy::Drop {
+            index.retain(|_, pair| pair.r2.is_some());
+        }
+        (index, orphans)
+    }
+
+    /// Add unpaired reads to a paired index as single-end members. A read
+    /// whose ID is already present as a pair keeps the pair.
+    pub fn add_singles(
+        &self,
+        index: &mut ReadIndex,
+        records: impl Iterator<Item = io::Result<fastq::Record>>,
+    ) {
+        for record in self.read(records, "unpaired") {
+            match index.entry(self.key(&record)) {
+                Entry::Occupied(_) => {
+                    warn!("Unpaired read {} is also present as a pair; keeping the pair", record.id())
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(PairedRecord::single(record));
+                }
+            }
+        }
+    }
+
+    /// Index an interleaved stream in one pass. Mates are paired by position
+    /// and keyed by the R1 ID; records next to each other that do not share
+    /// an ID, even without their `/1` and `/2` suffixes, are an error.
+    pub fn interleaved(&self, records: impl Iterator<Item = io::Result<fastq::Record>>) -> ReadIndex {
+        let mut index = HashMap::new();
+        let mut records = self.read(records, "interleaved");
+        while let Some(r1) = records.next() {
+            let Some(r2) = records.next() else {
+                if self.ignore_truncation {
+                    warn!("Dropping unpaired record {} at the end of the interleaved input", r1.id());
+                    break;
+                }
+                error!("Interleaved input ends with unpaired record {}", r1.id());
+                error!("Pass --ignore-truncation to drop it and continue");
+                process::exit(1);
+            };
+            if !self.are_mates(&r1, &r2) {
+                error!("Records {} and {} of the interleaved input are not mates", r1.id(), r2.id());
+                error!("Mates must be next to each other; pass --normalize-ids if their IDs are spelt differently");
+                process::exit(1);
+            }
+            self.insert(&mut index, self.key(&r1), PairedRecord::paired(r1, r2), "interleaved");
+        }
+        index
+    }
+
+    /// Index a paired stream whose mates share a name but need not be next
+    /// to each other, such as a coordinate-sorted BAM or CRAM. A mate
+    /// flagged as the first segment becomes R1, one flagged as the last
+    /// segment R2; mates without alignment flags, as unmapped reads
+    /// are, keep their input order. Records whose mate never turns up are
+    /// handled per `unpaired`, R1 being the records not flagged as the last
+    /// segment, and also returned.
+    pub fn by_name(
+        &self,
+        records: impl Iterator<Item = io::Result<fastq::Record>>,
+    ) -> (ReadIndex, Vec<fastq::Record>) {
+        let mut index = HashMap::new();
+        let mut waiting: HashMap<String, fastq::Record> = HashMap::new();
+        for record in self.read(records, "interleaved") {
+            let key = self.key(&record);
+            let Some(mate) = waiting.remove(&key) else {
+                waiting.insert(key, record);
+                continue;
+            };
+            let (r1, r2) = if segment(&record) == Some(Segment::First) || segment(&mate) == Some(Segment::Last) {
+                (record, mate)
+            } else {
+                (mate, record)
+            };
+            self.insert(&mut index, key, PairedRecord::paired(r1, r2), "interleaved");
+        }
+        let mut orphans: Vec<_> = waiting.into_values().collect();
+        orphans.sort_by(|a, b| a.id().cmp(b.id()));
+        match self.unpaired {
+            UnpairedPolicy::Error if !orphans.is_empty() => {
+                error!("{} reads have no mate in the input, e.g. {}", orphans.len(), orphans[0].id());
+                process::exit(1);
+            }
+            UnpairedPolicy::R1Only => {
+                for orphan in orphans.iter().filter(|orphan| segment(orphan) != Some(Segment::Last)) {
+                    self.insert(&mut index, self.key(orphan), PairedRecord::single(orphan.clone()), "interleaved");
+                }
+            }
+            _ => {}
+        }
+        (index, orphans)
+    }
+
+    /// Whether `r1` and `r2` share an ID, as normalised or without mate
+    /// suffixes.
+    fn are_mates(&self, r1: &fastq::Record, r2: &fastq::Record) -> bool {
+        let same = |ids: IdNormalization| ids.normalize(r1.id()) == ids.normalize(r2.id());
+        same(self.ids) || same(IdNormalization::Mate)
+    }
+}
@@ -0,0 +1,279 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use bio::io::{fasta, fastq};
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use liblzma::read::XzDecoder;
+use crate::alignment::{BamRecords, CramRecords, BAM_MAGIC, CRAM_MAGIC};
+use crate::record::SeqFormat;
+use crate::remote::{is_remote, open_remote};
+
+/// Compression formats recognised on input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+impl Compression {
+    /// Identify the compression format from the leading bytes of a stream.
+    pub fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else if magic.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Path that selects standard input instead of a file.
+pub const STDIN_PATH: &str = "-";
+
+/// Whether `path` refers to standard input, either as `-` or `/dev/stdin`.
+pub fn is_stdin(path: &str) -> bool {
+    path == STDIN_PATH || path == "/dev/stdin"
+}
+
+/// Open an input file, transparently decompressing it based on its magic bytes
+/// rather than its extension. A path of `-` reads from standard input, and
+/// http(s):// and s3:// URLs are streamed when built with the `remote` feature.
+///
+/// Inputs are only ever read front to back, never seeked, so pipes, FIFOs and
+/// process substitution (`<(zcat reads.fq.gz)`) work like regular files.
+pub fn open_input(path: &str) -> io::Result<Box<dyn Read>> {
+    if is_stdin(path) {
+        decode(io::stdin().lock())
+    } else if is_remote(path) {
+        decode(open_remote(path)?)
+    } else {
+        decode(File::open(path)?)
+    }
+}
+
+/// Number of leading bytes needed to recognise every supported compression.
+const MAGIC_LEN: usize = 6;
+
+/// Number of leading bytes inspected to recognise the read format.
+const FORMAT_PEEK_LEN: usize = 256;
+
+/// A stream with its peeked leading bytes put back in front.
+type Peeked<R> = io::Chain<io::Cursor<Vec<u8>>, R>;
+
+/// Read up to `len` leading bytes of a stream and hand back a reader that still
+/// yields them. Short reads are retried, so a pipe delivering its first bytes
+/// in small chunks is sniffed as reliably as a regular file.
+fn peek<R: Read>(mut reader: R, len: usize) -> io::Result<(Vec<u8>, Peeked<R>)> {
+    let mut head = Vec::with_capacity(len);
+    (&mut reader).take(len as u64).read_to_end(&mut head)?;
+    Ok((head.clone(), io::Cursor::new(head).chain(reader)))
+}
+
+/// Wrap a stream in the decoder matching its leading bytes.
+fn decode<R: Read + 'static>(reader: R) -> io::Result<Box<dyn Read>> {
+    let (magic, reader) = peek(reader, MAGIC_LEN)?;
+    let reader = BufReader::new(reader);
+
+    match Compression::detect(&magic) {
+        Compression::Gzip => Ok(Box::new(MultiGzDecoder::new(reader))),
+        Compression::Zstd => Ok(Box::new(zstd::stream::read::Decoder::with_buffer(reader)?)),
+        Compression::Bzip2 => Ok(Box::new(MultiBzDecoder::new(reader))),
+        Compression::Xz => Ok(Box::new(XzDecoder::new_multi_decoder(reader))),
+        Compression::None => Ok(Box::new(reader)),
+    }
+}
+
+/// Reads parsed from a FASTQ or FASTA stream.
+pub type Records = Box<dyn Iterator<Item = io::Result<fastq::Record>>>;
+
+/// Open a read file, decompress it, and parse it as `format`, or detect the
+/// format from its first record when `format` is `None`. `reference` is the
+/// FASTA used to decode CRAM input. Read errors, such as a truncated gzip
+/// member, name the file and the number of the record being read.
+pub fn open_reads(
+    path: &str,
+    format: Option<SeqFormat>,
+    reference: Option<&str>,
+) -> io::Result<(SeqFormat, Records)> {
+    let (format, records) = parse_reads(open_input(path)?, format, reference)?;
+    let path = path.to_string();
+    let mut record_number = 0;
+    Ok((
+        format,
+        Box::new(records.map(move |result| {
+            record_number += 1;
+            result.map_err(|e| io::Error::new(e.kind(), format!("{}, record {}: {}", path, record_number, e)))
+        })),
+    ))
+}
+
+/// Parse an opened, decompressed read stream as `format`, or as the format
+/// detected from its first record when `format` is `None`.
+fn parse_reads(
+    input: Box<dyn Read>,
+    format: Option<SeqFormat>,
+    reference: Option<&str>,
+) -> io::Result<(SeqFormat, Records)> {
+    let (head, reader) = peek(input, FORMAT_PEEK_LEN)?;
+    let format = match format {
+        Some(format) => format,
+        None => detect_format(&head)?,
+    };
+    Ok((format, read_records(BufReader::new(reader), format, reference)?))
+}
+
+/// Open several read files as one logical stream, e.g. the lanes of a
+/// multi-lane sample, read back to back. All files must share a format.
+pub fn open_lanes(
+    paths: &[String],
+    format: Option<SeqFormat>,
+    reference: Option<&str>,
+) -> io::Result<(SeqFormat, Records)> {
+    let mut lanes = Vec::new();
+    let mut lane_format = None;
+    for path in paths {
+        let (format, records) = open_reads(path, format, reference)?;
+        if let Some(previous) = lane_format
+            && previous != format
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is {:?} but the preceding files are {:?}", path, format, previous),
+            ));
+        }
+        lane_format = Some(format);
+        lanes.push(records);
+    }
+    Ok((lane_format.unwrap_or(SeqFormat::Fastq), Box::new(lanes.into_iter().flatten())))
+}
+
+/// Tell BAM and CRAM from their magic numbers and FASTA from FASTQ by the
+/// first record marker (`>` or `@`) in the leading bytes `head`.
+fn detect_format(head: &[u8]) -> io::Result<SeqFormat> {
+    if head.starts_with(BAM_MAGIC) {
+        return Ok(SeqFormat::Bam);
+    }
+    if head.starts_with(CRAM_MAGIC) {
+        return Ok(SeqFormat::Cram);
+    }
+    match head.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'>') => Ok(SeqFormat::Fasta),
+        Some(b'@') | None => Ok(SeqFormat::Fastq),
+        Some(&b) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Not a FASTA, FASTQ, BAM or CRAM file: first record starts with {:?}", b as char),
+        )),
+    }
+}
+
+/// Parse a FASTQ stream with rust-bio, which also accepts multi-line records.
+fn fastq_records<R: BufRead + 'static>(input: R) -> Records {
+    Box::new(fastq::Reader::from_bufread(input).records().map(|result| {
+        result.map_err(|e| match e {
+            fastq::Error::ReadError(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        })
+    }))
+}
+
+/// Iterate over the reads of a FASTQ, FASTA, BAM or CRAM stream. Reads are
+/// returned as FASTQ records, with empty qualities for FASTA, so the rest of
+/// the pipeline can treat all formats alike.
+pub fn read_records<R: BufRead + 'static>(
+    input: R,
+    format: SeqFormat,
+    reference: Option<&str>,
+) -> io::Result<Records> {
+    Ok(match format {
+        SeqFormat::Fastq => fastq_records(input),
+        SeqFormat::Fasta => Box::new(fasta::Reader::from_bufread(input).records().map(|result| {
+            result.map(|r| fastq::Record::with_attrs(r.id(), r.desc(), r.seq(), &[]))
+        })),
+        SeqFormat::Bam => Box::new(BamRecords::new(input)?),
+        SeqFormat::Cram => Box::new(CramRecords::new(input, reference)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use flate2::write::GzEncoder;
+    use crate::clusters::{read_clusters, ClusterFileFormat};
+    use super::*;
+
+    const FASTQ: &[u8] = b"@read1 1:N:0:ACGT\nACGTAC\n+\nIIIIII\n@read2 1:N:0:ACGT\nTTGCAA\n+\nIIIIII\n";
+    const CLSTR: &[u8] = b">Cluster 0\n0\t6nt, >read1... *\n1\t6nt, >read2... at +/100.00%\n";
+
+    /// A pipe: hands out at most `chunk` bytes per read and cannot seek.
+    struct Pipe {
+        data: Vec<u8>,
+        pos: usize,
+        chunk: usize,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.chunk).min(self.data.len() - self.pos);
+            buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+            self.pos += len;
+            Ok(len)
+        }
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// `data` plain and gzipped, each through pipes delivering one and three
+    /// bytes at a time, decompressed like [`open_input`] does.
+    fn piped(data: &[u8]) -> Vec<Box<dyn Read>> {
+        let mut inputs = Vec::new();
+        for bytes in [data.to_vec(), gzip(data)] {
+            for chunk in [1, 3] {
+                inputs.push(decode(Pipe { data: bytes.clone(), pos: 0, chunk }).unwrap());
+            }
+        }
+        inputs
+    }
+
+    #[test]
+    fn decoded_pipe_yields_every_byte() {
+        for mut input in piped(FASTQ) {
+            let mut out = Vec::new();
+            input.read_to_end(&mut out).unwrap();
+            assert_eq!(out, FASTQ);
+        }
+    }
+
+    #[test]
+    fn reads_from_a_pipe() {
+        // The R1 and R2 inputs are opened alike
+        for input in piped(FASTQ) {
+            let (format, records) = parse_reads(input, None, None).unwrap();
+            assert_eq!(format, SeqFormat::Fastq);
+            let ids: Vec<String> = records.map(|r| r.unwrap().id().to_string()).collect();
+            assert_eq!(ids, ["read1", "read2"]);
+        }
+    }
+
+    #[test]
+    fn clusters_from_a_pipe() {
+        for input in piped(CLSTR) {
+            let clusters: Vec<_> =
+                read_clusters(BufReader::new(input), ClusterFileFormat::Auto).unwrap().map(Result::unwrap).collect();
+            assert_eq!(clusters.len(), 1);
+            let ids: Vec<&str> = clusters[0].members.iter().map(|m| m.id.as_str()).collect();
+            assert_eq!(ids, ["read1", "read2"]);
+        }
+    }
+}
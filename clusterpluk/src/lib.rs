@@ -0,0 +1,19 @@
+//! Pluck the best read from each read cluster based on consensus and phred
+//! quality. The `ClusterPluk` binary is a thin command line front end; the
+//! modules below can be embedded to read clusters, index reads and select
+//! representatives from other tools.
+
+mod alignment;
+mod clstr;
+mod remote;
+
+pub mod clusters;
+pub mod consensus;
+pub mod expr;
+pub mod ids;
+pub mod index;
+pub mod input;
+pub mod merge;
+pub mod output;
+pub mod record;
+pub mod select;
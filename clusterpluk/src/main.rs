@@ -1,127 +1,1746 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
-use regex::Regex;
-use log::{info, warn, error};
-use bio::io::fastq;
-use std::env;
-
-fn main() {
-    env_logger::init();
-
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 6 {
-        eprintln!("Usage: {} <R1.fastq> <R2.fastq> <clusters.clstr> <output_R1.fastq> <output_R2.fastq>", args[0]);
-        std::process::exit(1);
-    }
-
-    let r1_file = &args[1];
-    let r2_file = &args[2];
-    let cluster_file = &args[3];
-    let r1_out = &args[4];
-    let r2_out = &args[5];
-
-    sort_fastq_by_quality(r1_file, r2_file, cluster_file, r1_out, r2_out);
-}
-
-fn sort_fastq_by_quality(
-    r1_input: &str,
-    r2_input: &str,
-    cluster_file: &str,
-    r1_output: &str,
-    r2_output: &str,
-) {
-    let r1_reader = fastq::Reader::from_file(r1_input).expect("Failed to open R1 FASTQ");
-    let r2_reader = fastq::Reader::from_file(r2_input).expect("Failed to open R2 FASTQ");
-
-    info!("\nCreating index for: {}", r1_input);
-    let mut r1_index = HashMap::new();
-    for result in r1_reader.records() {
-        let record = result.expect("Error reading R1 record");
-        r1_index.insert(record.id().to_string(), record.clone());
-    }
-
-    info!("\nCreating index for: {}", r2_input);
-    let mut r2_index = HashMap::new();
-    for result in r2_reader.records() {
-        let record = result.expect("Error reading R2 record");
-        r2_index.insert(record.id().to_string(), record.clone());
-    }
-
-    let re = Regex::new(r".*>(.*)\.\.\.").unwrap();
-    let reader = BufReader::new(File::open(cluster_file).expect("Could not open cluster file"));
-    let mut out1 = fastq::Writer::to_file(r1_output).expect("Failed to create output R1 file");
-    let mut out2 = fastq::Writer::to_file(r2_output).expect("Failed to create output R2 file");
-    // let mut out1 = File::create(r1_output).expect("Failed to create output R1 file");
-    // let mut out2 = File::create(r2_output).expect("Failed to create output R2 file");
-
-    let mut cluster: Vec<(fastq::Record, fastq::Record)> = Vec::new();
-    let mut cluster_count = 0;
-
-    for (i, line) in reader.lines().enumerate() {
-        let line = line.expect("Failed to read line");
-        if i == 0 {
-            continue; // skip header
-        }
-
-        if line.starts_with('>') {
-            cluster_count += 1;
-            print!("\rProcessing cluster: {}", cluster_count);
-            std::io::stdout().flush().unwrap();
-
-            let (r1_best, r2_best) = if cluster.len() == 1 {
-                cluster[0].clone()
-            } else {
-                pluck_best_read_from_cluster(&cluster)
-            };
-            out1.write_record(&r1_best).expect("Failed to write to R1 file");
-            out2.write_record(&r2_best).expect("Failed to write to R2 file");
-
-            cluster.clear();
-        } else {
-            if let Some(cap) = re.captures(&line) {
-                let id = &cap[1];
-                if let (Some(r1), Some(r2)) = (r1_index.get(id), r2_index.get(id)) {
-                    cluster.push((r1.clone(), r2.clone()));
-                } else {
-                    error!("Read ID {} not found in FASTQ files", id);
-                }
-            } else {
-                warn!("Malformed line: {}", line);
-            }
-        }
-    }
-
-    println!("\nProcessing complete. Clusters processed: {}", cluster_count);
-}
-
-fn pluck_best_read_from_cluster(cluster: &[(fastq::Record, fastq::Record)]) -> (fastq::Record, fastq::Record) {
-    let mut scores = Vec::new();
-    let mut seqs = Vec::new();
-
-    for (r1, r2) in cluster {
-        let full_seq: Vec<u8> = r1.seq().iter().chain(r2.seq()).copied().collect();
-        let full_qual: Vec<u8> = r1.qual().iter().chain(r2.qual()).copied().collect();
-
-        let avg_error: f64 = full_qual
-            .iter()
-            .map(|&q| 10f64.powf(-(q as f64 - 33.0) / 10.0))
-            .sum::<f64>() / full_qual.len() as f64;
-
-        scores.push(avg_error);
-        seqs.push(full_seq);
-    }
-
-    let mut idxs: Vec<usize> = (0..scores.len()).collect();
-    idxs.sort_by(|&i, &j| scores[i].partial_cmp(&scores[j]).unwrap());
-
-    let mut counts = HashMap::new();
-    for seq in &seqs {
-        *counts.entry(seq.clone()).or_insert(0) += 1;
-    }
-
-    let consensus_seq = counts.into_iter().max_by_key(|&(_, c)| c).unwrap().0;
-    let best_index = idxs.into_iter().find(|&i| seqs[i] == consensus_seq).unwrap();
-
-    cluster[best_index].clone()
-}
\ No newline at end of file
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use log::{info, warn, error};
+use clap::{Parser, Subcommand};
+use rayon::prelude::*;
+use bio::io::fasta;
+use rand::rngs::StdRng;
+use rand::seq::IteratorRandom;
+use rand::SeedableRng;
+
+mod batch;
+mod consensus_command;
+
+use batch::read_samplesheet;
+use consensus_command::{run_consensus, ConsensusArgs};
+use clusterpluk::clusters::{merge_clusters, read_clusters, Cluster, ClusterFileFormat, Member, Strand};
+use clusterpluk::consensus::{align_to, identity, Consensus, ConsensusMethod, ConsensusRead, Pileup};
+use clusterpluk::expr::Expr;
+use clusterpluk::ids::{sample_prefix, IdNormalization, UmiFormat};
+use clusterpluk::index::{DuplicatePolicy, Indexer, ReadIndex, UnpairedPolicy};
+use clusterpluk::input::{is_stdin, open_input, open_lanes};
+use clusterpluk::merge::Merger;
+use clusterpluk::output::{is_stdout, Codec, OutputCompression, OutputFormat, PairWriter, RecordWriter};
+use clusterpluk::record::{PairedRecord, SeqFormat};
+use clusterpluk::select::{
+    detect_phred_offset, farthest_points, quality_range, validate_quality_range, Candidate, MaxN, QualityScore,
+    Primers, ScoreMate, Selection, SlidingWindow, Strategy, Weights, PHRED33, PHRED64,
+};
+
+/// Pluck the best read from each read cluster based on consensus and phred quality
+#[derive(Parser, Clone)]
+#[command(version, about, subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Input R1 FASTQ file, the interleaved FASTQ with --interleaved, or the reads with --single ('-' for stdin).
+    /// Repeat for multi-lane samples; the files are read as if concatenated
+    #[arg(short, long, visible_alias = "r1", required_unless_present = "samplesheet")]
+    input: Vec<String>,
+
+    /// Input R2 FASTQ file ('-' for stdin). Repeat for multi-lane samples
+    #[arg(
+        long,
+        visible_alias = "r2",
+        required_unless_present_any = ["interleaved", "single", "long_reads", "samplesheet"],
+        conflicts_with_all = ["interleaved", "single", "long_reads"]
+    )]
+    input2: Vec<String>,
+
+    /// Input cluster file, optionally compressed like the reads (e.g. .clstr.gz) ('-' for stdin).
+    /// Repeat for data clustered in chunks; clusters are then renumbered across all files
+    #[arg(short, long, required_unless_present = "samplesheet")]
+    cluster: Vec<String>,
+
+    /// Format of the --cluster file
+    #[arg(long, value_enum, default_value_t = ClusterFileFormat::Auto)]
+    cluster_format: ClusterFileFormat,
+
+    /// Output R1 FASTQ file, or the only output with --single or when --output2 is omitted with --interleaved
+    /// ('-' for stdout)
+    #[arg(short, long, required_unless_present_any = ["samplesheet", "cluster_dir"])]
+    output: Option<String>,
+
+    /// Output R2 FASTQ file
+    #[arg(
+        long,
+        required_unless_present_any = [
+            "interleaved",
+            "interleave_output",
+            "single",
+            "long_reads",
+            "samplesheet",
+            "cluster_dir"
+        ],
+        conflicts_with_all = ["single", "long_reads", "interleave_output"]
+    )]
+    output2: Option<String>,
+
+    /// Write the selected pairs to --output alone, each R1 followed by its R2, even when the
+    /// mates are read from separate files
+    #[arg(long, conflicts_with_all = ["single", "long_reads"])]
+    interleave_output: bool,
+
+    /// Process every sample of a CSV/TSV samplesheet with `sample, R1, R2, clstr, out_prefix`
+    /// columns (R2 empty for single-end samples), writing <out_prefix>_R1.fastq and
+    /// <out_prefix>_R2.fastq, or <out_prefix>.fastq, per sample. All other options apply to
+    /// every sample
+    #[arg(long, conflicts_with_all = ["input", "input2", "cluster", "output", "output2", "interleaved"])]
+    samplesheet: Option<String>,
+
+    /// Write a per-sample summary TSV of the --samplesheet run
+    #[arg(long, requires = "samplesheet")]
+    summary: Option<String>,
+
+    /// Read R1 and R2 from a single --input file in which the mates alternate
+    #[arg(long, conflicts_with = "single")]
+    interleaved: bool,
+
+    /// Single-end mode: pick the best read per cluster from one --input file
+    #[arg(long)]
+    single: bool,
+
+    /// Long-read (e.g. Nanopore) mode: single-end, ranking members by median base quality
+    /// instead of the mean error over all bases, without the exact-sequence vote
+    #[arg(long, conflicts_with = "interleaved")]
+    long_reads: bool,
+
+    /// Unpaired reads (e.g. fastp's unpaired output) that take part in clusters as single-end
+    /// members. Repeat for multi-lane samples
+    #[arg(long, conflicts_with_all = ["single", "long_reads", "samplesheet"], requires = "output_singles")]
+    singles: Vec<String>,
+
+    /// Output file for clusters won by a single-end member, from --singles or kept by
+    /// --unpaired-policy r1-only
+    #[arg(long)]
+    output_singles: Option<String>,
+
+    /// What to do with reads whose ID is in only one of the R1 and R2 files. `r1-only` keeps
+    /// R1 reads without a mate as single-end members (written to --output-singles when the
+    /// mates go to separate files)
+    #[arg(long, value_enum, default_value_t = UnpairedPolicy::Drop)]
+    unpaired_policy: UnpairedPolicy,
+
+    /// Write the reads whose ID is in only one of the R1 and R2 files here
+    #[arg(long, conflicts_with_all = ["single", "long_reads", "interleaved"])]
+    output_orphans: Option<String>,
+
+    /// I1 index reads to keep in sync with the selected pairs. Repeat for multi-lane samples
+    #[arg(long, requires = "output_i1", conflicts_with = "samplesheet")]
+    i1: Vec<String>,
+
+    /// I2 index reads to keep in sync with the selected pairs. Repeat for multi-lane samples
+    #[arg(long, requires = "output_i2", conflicts_with = "samplesheet")]
+    i2: Vec<String>,
+
+    /// Output file for the I1 reads of the selected pairs
+    #[arg(long, requires = "i1")]
+    output_i1: Option<String>,
+
+    /// Output file for the I2 reads of the selected pairs
+    #[arg(long, requires = "i2")]
+    output_i2: Option<String>,
+
+    /// Compress every read output with this codec [default when given bare: gzip]. Outputs
+    /// ending in .gz, .bgz or .zst are compressed accordingly regardless
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "gzip")]
+    compress_output: Option<Codec>,
+
+    /// Compression level of the read outputs: 0 (fastest) to 9 for gzip and bgzf, up to 22
+    /// for zstd [default: 6 for gzip and bgzf, 3 for zstd]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=22))]
+    compression_level: Option<u32>,
+
+    /// Format of the input reads [default: detected from the first record]. FASTA has
+    /// no qualities, so clusters are represented by their most abundant sequence,
+    /// preferring the longest. A paired unaligned BAM is read with --interleaved
+    #[arg(long, value_enum)]
+    input_format: Option<SeqFormat>,
+
+    /// Format of the read outputs [default: FASTA for FASTA input, FASTQ otherwise]. FASTA
+    /// drops the qualities, roughly halving the output
+    #[arg(long, value_enum)]
+    output_format: Option<OutputFormat>,
+
+    /// FASTQ qualities are phred+64 encoded (Illumina 1.3-1.7, e.g. GAIIx) rather than phred+33.
+    /// Output qualities are written unchanged [default: detected from the first reads]
+    #[arg(long)]
+    phred64: bool,
+
+    /// FASTQ qualities are phred+33 encoded, skipping detection
+    #[arg(long, conflicts_with = "phred64")]
+    phred33: bool,
+
+    /// How read IDs are normalised before FASTQ records and cluster members are matched.
+    /// `mate` drops everything after the first whitespace and a trailing /1 or /2;
+    /// `casava` keys Illumina 1.8+ reads by instrument:run:flowcell:lane:tile:x:y
+    #[arg(long, value_enum, default_value_t = IdNormalization::None)]
+    normalize_ids: IdNormalization,
+
+    /// What to do when a read ID occurs more than once in an input
+    #[arg(long, value_enum, default_value_t = DuplicatePolicy::Warn)]
+    on_duplicate: DuplicatePolicy,
+
+    /// Keep the reads recovered from a truncated or corrupt input instead of aborting
+    #[arg(long)]
+    ignore_truncation: bool,
+
+    /// How the member written for each cluster is chosen. `representative` writes the cd-hit `*`
+    /// member, the VSEARCH centroid, ..., falling back to `best-quality` for clusters without one
+    #[arg(long, value_enum, default_value_t = Strategy::BestQuality)]
+    select: Strategy,
+
+    /// How members are ranked by base quality [default: mean-error, or median-quality with
+    /// --long-reads]
+    #[arg(long, value_enum)]
+    score: Option<QualityScore>,
+
+    /// Phred quality below which a base counts against its read with --score low-quality-bases
+    #[arg(long, default_value_t = 20)]
+    low_quality: u8,
+
+    /// Count sequences within this many mismatches of each other as copies in the exact-sequence
+    /// vote, so reads differing by a sequencing error do not split it. The winner's exact copies
+    /// are then ranked by quality
+    #[arg(long, default_value_t = 0)]
+    vote_mismatches: u64,
+
+    /// Only trust the exact-sequence vote of `best-quality` if the most abundant sequence has at
+    /// least this many copies; otherwise pick the best-quality member of the whole cluster
+    #[arg(long, default_value_t = 1)]
+    vote_min_copies: usize,
+
+    /// Only trust the exact-sequence vote if the most abundant sequence makes up at least this
+    /// percentage of the cluster
+    #[arg(long, value_parser = parse_percentage, default_value_t = 0.0)]
+    vote_min_fraction: f64,
+
+    /// Pass over members with more N bases than this, either a count (`2`) or a fraction of the
+    /// read (`0.01`). Clusters in which every member exceeds it are picked from as usual
+    #[arg(long)]
+    max_n: Option<MaxN>,
+
+    /// Pass over members with any base below this phred quality (e.g. 10), so the output never
+    /// carries an obviously bad base however good its average. Clusters in which every member
+    /// fails are picked from as usual
+    #[arg(long)]
+    min_base_quality: Option<u8>,
+
+    /// Pass over low-complexity members such as poly-A reads and adapter concatemers: those whose
+    /// trinucleotide entropy, scaled from 0 (homopolymer) to 1, is below this, e.g. 0.5
+    #[arg(long)]
+    min_complexity: Option<f64>,
+
+    /// FASTA of amplicon primers, forward and reverse primer of each pair in turn (5' to 3').
+    /// Members not starting with a forward primer, or lacking its reverse primer at the start of
+    /// R2 (the end of a single-end read), are passed over, so truncated amplicons are not picked
+    #[arg(long)]
+    primers: Option<String>,
+
+    /// Score only the bases of each read before a sliding window of this many bases first
+    /// averages below this quality, e.g. `4:20`, so a junk tail does not count against an
+    /// otherwise good read
+    #[arg(long)]
+    score_trim: Option<SlidingWindow>,
+
+    /// Expression ranked on by `--select expression`, highest first, e.g.
+    /// "mean_q - 0.01*n_count + 0.001*len". Variables: len, mean_q, ee (expected errors),
+    /// n_count, identity (percent, 100 when not recorded) and complexity (0 to 1); operators:
+    /// + - * / ^ and parentheses
+    #[arg(long, required_if_eq("select", "expression"), allow_hyphen_values = true)]
+    score_expr: Option<Expr>,
+
+    /// Score only R1 or only R2 qualities, e.g. when R2 is systematically worse and drags the
+    /// combined score down. Both mates of the chosen pair are still written
+    #[arg(long, value_enum, default_value_t = ScoreMate::Both)]
+    score_mate: ScoreMate,
+
+    /// With aligned BAM/CRAM input, rank members by alignment before base quality: properly
+    /// paired first, then mapped, then by mapping quality
+    #[arg(long)]
+    prefer_aligned: bool,
+
+    /// Weight of base quality (one minus the mean error probability) in the `weighted` strategy
+    #[arg(long, default_value_t = 1.0)]
+    weight_quality: f64,
+
+    /// Weight of length relative to the longest member in the `weighted` strategy
+    #[arg(long, default_value_t = 0.0)]
+    weight_length: f64,
+
+    /// Weight of identity to the representative (as a fraction) in the `weighted` strategy
+    #[arg(long, default_value_t = 0.0)]
+    weight_identity: f64,
+
+    /// Weight of sequence complexity (0 for a homopolymer to 1) in the `weighted` strategy
+    #[arg(long, default_value_t = 0.0)]
+    weight_complexity: f64,
+
+    /// Write the best N pairs of every cluster, best first, e.g. as supporting reads for a
+    /// consensus tool. Clusters with fewer members are written whole
+    #[arg(long, default_value = "1")]
+    per_cluster: NonZeroUsize,
+
+    /// Pick the --per-cluster pairs to span the cluster's diversity rather than as the next best:
+    /// the strategy's pick, then each time the member with the largest k-mer distance to the
+    /// pairs picked so far. Pairs are written with a _rep1, _rep2, ... suffix
+    #[arg(long)]
+    diverse: bool,
+
+    /// Leave clusters listing more members than this out of the output, e.g. to drop adapter
+    /// dimers or host rRNA
+    #[arg(long)]
+    max_cluster_size: Option<usize>,
+
+    /// Write the picks of clusters over --max-cluster-size here for inspection, mates
+    /// interleaved
+    #[arg(long, requires = "max_cluster_size")]
+    output_large: Option<String>,
+
+    /// Write the picks of clusters listing a single member here instead, R1 for pairs, as
+    /// singletons are often errors. Mates are interleaved in it when --output-singletons2 is
+    /// omitted
+    #[arg(long)]
+    output_singletons: Option<String>,
+
+    /// Output R2 file for the picks of single-member clusters
+    #[arg(long, requires = "output_singletons", conflicts_with = "output_singles")]
+    output_singletons2: Option<String>,
+
+    /// Write every member of the written clusters instead of only the picks, the others tagged
+    /// DUP:cluster<ID> in their header so downstream tools can decide what to drop
+    #[arg(long, conflicts_with = "output_duplicates")]
+    mark_duplicates: bool,
+
+    /// Write the members of the written clusters that were not picked here, mates interleaved,
+    /// so no read is lost
+    #[arg(long)]
+    output_duplicates: Option<String>,
+
+    /// Write every pair that was not picked here, R1 for pairs: the other members of the written
+    /// clusters and all members of the clusters skipped or dropped. Mates are interleaved in it
+    /// when --discarded-r2 is omitted
+    #[arg(long)]
+    discarded_r1: Option<String>,
+
+    /// Write the R2 mates of the pairs that were not picked here
+    #[arg(long, requires = "discarded_r1")]
+    discarded_r2: Option<String>,
+
+    /// Pick from the members of every sample separately, the sample being the start of the read
+    /// ID up to this delimiter (e.g. `_` for `S1_read17`), and write each sample's picks to its
+    /// own files, named after --output and --output2 with a `<sample>_` prefix
+    #[arg(long, conflicts_with_all = ["output_singles", "output_i1", "output_i2"])]
+    sample_delimiter: Option<char>,
+
+    /// Write the picks of every cluster to files of their own in this directory instead,
+    /// cluster_<ID>_R1.fastq and cluster_<ID>_R2.fastq, or cluster_<ID>.fastq for single-end
+    /// and interleaved output (numeric IDs padded to six digits). With --mark-duplicates every
+    /// member goes there
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "output",
+            "output2",
+            "samplesheet",
+            "sample_delimiter",
+            "output_singles",
+            "output_i1",
+            "output_i2"
+        ]
+    )]
+    cluster_dir: Option<String>,
+
+    /// Append the USEARCH-style abundance annotation ;size=N, N being the number of members
+    /// the cluster lists, to the IDs of the picks, e.g. for vsearch --sortbysize or chimera
+    /// detection
+    #[arg(long)]
+    size_annotation: bool,
+
+    /// Append cluster=<ID> to the header of every pick, after its ID, so each written read can
+    /// be traced back to its cluster
+    #[arg(long)]
+    tag_cluster: bool,
+
+    /// Write a TSV of every pick: its cluster, read ID, the number of members the cluster
+    /// lists and the value the --select strategy ranked it by (e.g. the quality score), to
+    /// audit which read won each cluster and why
+    #[arg(long)]
+    selection_table: Option<String>,
+
+    /// Collapse members sharing a UMI, read from the headers in this format, to their best one
+    /// before picking, so PCR duplicates of one molecule count once
+    #[arg(long, value_enum)]
+    umi: Option<UmiFormat>,
+
+    /// Randomly sample clusters with more members down to this many before selection, bounding
+    /// the time and memory spent on huge clusters. The representative is always kept with
+    /// `--select representative`
+    #[arg(long)]
+    max_reads_per_cluster: Option<NonZeroUsize>,
+
+    /// Seed for the random sampling of --max-reads-per-cluster; the same seed gives the same
+    /// output
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Skip clusters listing fewer members than this in the cluster file, e.g. 2 to drop
+    /// singletons, which are often sequencing errors
+    #[arg(long, default_value_t = 1)]
+    min_cluster_size: usize,
+
+    /// Also write every written pair merged into one contig where its mates overlap, FLASH-style,
+    /// to this file; pairs without an acceptable overlap are left out of it and counted
+    #[arg(long)]
+    merge_output: Option<String>,
+
+    /// Fewest overlapping bases for --merge-output to merge a pair
+    #[arg(long, default_value_t = 10)]
+    merge_min_overlap: usize,
+
+    /// Highest percentage of mismatching bases in the overlap for --merge-output to merge a pair
+    #[arg(long, default_value_t = 25.0, value_parser = parse_percentage)]
+    merge_max_mismatch: f64,
+
+    /// Also write the consensus of every cluster to this file, next to the picks of a
+    /// non-consensus --select; the picks are tagged `consensus=cluster<ID>` and the consensus
+    /// `representative=<read ID>` of the first pick, linking the two
+    #[arg(long)]
+    output_consensus: Option<String>,
+
+    /// Output file for the R2 mates of --output-consensus; interleaved there if omitted
+    #[arg(long, requires = "output_consensus")]
+    output_consensus2: Option<String>,
+
+    /// How --output-consensus combines the members
+    #[arg(long, value_enum, default_value_t = ConsensusMethod::Majority)]
+    consensus_method: ConsensusMethod,
+
+    /// Number of threads building consensus sequences; the output is the same for any number
+    #[arg(long, default_value_t = NonZeroUsize::MIN)]
+    threads: NonZeroUsize,
+
+    /// With a consensus strategy, represent clusters listing fewer members than this by their
+    /// best-quality read instead, as a consensus of very few reads is unreliable
+    #[arg(long, default_value_t = 1)]
+    consensus_min_size: usize,
+
+    /// With a consensus strategy, weigh every base's vote by the probability that it is correct,
+    /// from its quality, so a few high-quality reads are not outvoted by many noisy ones
+    #[arg(long)]
+    weighted_vote: bool,
+
+    /// With a consensus strategy, write the IUPAC ambiguity code of all bases carried by at least
+    /// this percentage of the members at a position, e.g. 30, where there are several, rather
+    /// than picking one
+    #[arg(long, value_parser = parse_percentage)]
+    iupac: Option<f64>,
+
+    /// With a consensus strategy, polish the consensus this many times: the members are aligned
+    /// back to it and every position, deletion and insertion re-voted. Helps noisy long reads
+    #[arg(long, default_value_t = 0)]
+    consensus_rounds: usize,
+
+    /// With a consensus strategy, write a TSV of every cluster's member count and the mean and
+    /// lowest percent identity of its members to the consensus, to flag poorly agreeing clusters
+    #[arg(long)]
+    identity_stats: Option<String>,
+
+    /// Write a TSV of the positions where members disagree with the written read (the consensus
+    /// or the first pick) in at least --variant-min-frequency percent of them, to detect mixed
+    /// clusters
+    #[arg(long)]
+    variant_table: Option<String>,
+
+    /// Lowest percentage of the members sharing an alternative for --variant-table to list it
+    #[arg(long, default_value_t = 10.0, value_parser = parse_percentage)]
+    variant_min_frequency: f64,
+
+    /// With a consensus strategy, write the alignment of the members each consensus was called
+    /// from to this directory, one FASTA per cluster (and mate), for reviewing disagreements
+    #[arg(long)]
+    write_msa: Option<String>,
+
+    /// Drop clusters whose best member has a mean phred quality below this, e.g. 20, rather than
+    /// writing junk; dropped clusters are counted in the summary
+    #[arg(long)]
+    min_rep_quality: Option<f64>,
+
+    /// Only consider members whose identity to the cluster representative, as recorded in the
+    /// cluster file (cd-hit, VSEARCH), is at least this percentage, e.g. 99
+    #[arg(long, value_parser = parse_percentage)]
+    min_identity: Option<f64>,
+
+    /// Reverse complement members the cluster file records on the minus strand (cd-hit `-/`,
+    /// VSEARCH `-`) before voting and output, so they are compared and written in the
+    /// orientation of their representative
+    #[arg(long)]
+    orient: bool,
+
+    /// Reference FASTA (with .fai index) for decoding CRAM input
+    #[arg(long)]
+    reference: Option<String>,
+}
+
+/// Workflows other than plucking representatives.
+#[derive(Subcommand, Clone)]
+enum Command {
+    Consensus(ConsensusArgs),
+}
+
+fn main() {
+    env_logger::init();
+
+    let args = Args::parse_from(legacy_args(std::env::args().collect()));
+
+    if let Some(Command::Consensus(consensus_args)) = &args.command {
+        run_consensus(consensus_args);
+        return;
+    }
+
+    if let Some(samplesheet) = &args.samplesheet {
+        run_samplesheet(&args, samplesheet);
+        return;
+    }
+
+    let inputs = args.input.iter().chain(&args.input2).chain(&args.singles).chain(&args.i1).chain(&args.i2);
+    if inputs.chain(&args.cluster).filter(|path| is_stdin(path)).count() > 1 {
+        eprintln!("Only one input file can be read from stdin");
+        std::process::exit(1);
+    }
+    let to_stdout = read_outputs(&args).filter(|path| is_stdout(path)).count();
+    if to_stdout > 1 {
+        eprintln!("Only one output file can be written to stdout");
+        std::process::exit(1);
+    }
+    if to_stdout > 0 && args.sample_delimiter.is_some() {
+        eprintln!("--sample-delimiter writes a file per sample and cannot write to stdout");
+        std::process::exit(1);
+    }
+
+    sort_fastq_by_quality(&args);
+}
+
+/// The command line rewritten to flags when it is the deprecated positional form
+/// `ClusterPluk <R1.fastq> <R2.fastq> <clusters.clstr> <output_R1.fastq> <output_R2.fastq>`,
+/// so pipelines written for it keep working.
+fn legacy_args(args: Vec<String>) -> Vec<String> {
+    let positional = args.len() == 6 && args[1..].iter().all(|arg| !arg.starts_with('-') && arg != "consensus");
+    if !positional {
+        return args;
+    }
+    warn!("The positional form is deprecated; use -i R1 --input2 R2 -c CLUSTERS -o OUT1 --output2 OUT2");
+    let flags = ["-i", "--input2", "-c", "-o", "--output2"];
+    let rewritten = flags.iter().zip(&args[1..]).flat_map(|(flag, value)| [flag.to_string(), value.clone()]);
+    std::iter::once(args[0].clone()).chain(rewritten).collect()
+}
+
+/// Every read output given in `args`.
+fn read_outputs(args: &Args) -> impl Iterator<Item = &str> {
+    [
+        &args.output,
+        &args.output2,
+        &args.output_singles,
+        &args.output_orphans,
+        &args.output_i1,
+        &args.output_i2,
+        &args.output_large,
+        &args.output_singletons,
+        &args.output_singletons2,
+        &args.output_duplicates,
+        &args.discarded_r1,
+        &args.discarded_r2,
+        &args.merge_output,
+        &args.output_consensus,
+        &args.output_consensus2,
+    ]
+    .into_iter()
+    .filter_map(|path| path.as_deref())
+}
+
+/// Where progress and counts are printed: stdout, or stderr when the reads
+/// are written to stdout.
+fn report(reads_to_stdout: bool) -> Box<dyn Write> {
+    if reads_to_stdout { Box::new(io::stderr()) } else { Box::new(io::stdout()) }
+}
+
+/// Parse a percentage between 0 and 100.
+fn parse_percentage(s: &str) -> Result<f64, String> {
+    match s.trim_end_matches('%').parse::<f64>() {
+        Ok(pct) if (0.0..=100.0).contains(&pct) => Ok(pct),
+        _ => Err(format!("{} is not a percentage between 0 and 100", s)),
+    }
+}
+
+/// Counts reported after processing one sample.
+struct RunSummary {
+    reads_indexed: usize,
+    clusters: usize,
+}
+
+/// Run every sample of a samplesheet with the shared options in `args`, then
+/// print (and optionally write) an aggregate summary.
+fn run_samplesheet(args: &Args, samplesheet: &str) {
+    let samples = read_samplesheet(samplesheet)
+        .unwrap_or_else(|e| panic!("Failed to read samplesheet {}: {}", samplesheet, e));
+    let format = match (args.output_format, args.input_format) {
+        (Some(format), _) => format,
+        (None, Some(SeqFormat::Fasta)) => OutputFormat::Fasta,
+        (None, _) => OutputFormat::Fastq,
+    };
+    let extension = read_extension(format, args.compress_output);
+
+    let mut summaries = Vec::new();
+    for sample in &samples {
+        info!("\nProcessing sample: {}", sample.name);
+        let single = args.single || args.long_reads || sample.r2.is_none();
+        let (output, output2) = if single || args.interleave_output {
+            (format!("{}.{}", sample.out_prefix, extension), None)
+        } else {
+            (
+                format!("{}_R1.{}", sample.out_prefix, extension),
+                Some(format!("{}_R2.{}", sample.out_prefix, extension)),
+            )
+        };
+        let per_sample = |path: &Option<String>| path.as_deref().map(|path| prefixed_path(&sample.out_prefix, path));
+        let sample_args = Args {
+            input: vec![sample.r1.clone()],
+            input2: sample.r2.iter().cloned().collect(),
+            cluster: vec![sample.cluster.clone()],
+            output: Some(output),
+            output2,
+            single,
+            samplesheet: None,
+            output_singles: per_sample(&args.output_singles),
+            output_orphans: per_sample(&args.output_orphans),
+            output_large: per_sample(&args.output_large),
+            output_singletons: per_sample(&args.output_singletons),
+            output_singletons2: per_sample(&args.output_singletons2),
+            output_duplicates: per_sample(&args.output_duplicates),
+            discarded_r1: per_sample(&args.discarded_r1),
+            discarded_r2: per_sample(&args.discarded_r2),
+            selection_table: per_sample(&args.selection_table),
+            merge_output: per_sample(&args.merge_output),
+            output_consensus: per_sample(&args.output_consensus),
+            output_consensus2: per_sample(&args.output_consensus2),
+            identity_stats: per_sample(&args.identity_stats),
+            variant_table: per_sample(&args.variant_table),
+            write_msa: per_sample(&args.write_msa),
+            ..args.clone()
+        };
+        summaries.push(sort_fastq_by_quality(&sample_args));
+    }
+
+    let mut table = String::from("sample\treads_indexed\tclusters\n");
+    for (sample, summary) in samples.iter().zip(&summaries) {
+        table += &format!("{}\t{}\t{}\n", sample.name, summary.reads_indexed, summary.clusters);
+    }
+    let total_reads: usize = summaries.iter().map(|s| s.reads_indexed).sum();
+    let total_clusters: usize = summaries.iter().map(|s| s.clusters).sum();
+    table += &format!("total\t{}\t{}\n", total_reads, total_clusters);
+
+    println!("\nSamplesheet complete. Samples processed: {}\n{}", samples.len(), table);
+    if let Some(summary) = &args.summary {
+        File::create(summary)
+            .and_then(|mut file| file.write_all(table.as_bytes()))
+            .unwrap_or_else(|e| panic!("Failed to write summary {}: {}", summary, e));
+    }
+}
+
+fn sort_fastq_by_quality(args: &Args) -> RunSummary {
+    if args.unpaired_policy == UnpairedPolicy::R1Only && args.output2.is_some() && args.output_singles.is_none() {
+        eprintln!("--unpaired-policy r1-only with --output2 needs --output-singles for the R1-only reads");
+        std::process::exit(1);
+    }
+    let compression = OutputCompression { codec: args.compress_output, level: args.compression_level };
+
+    let open = |paths: &[String], what: &str| {
+        open_lanes(paths, args.input_format, args.reference.as_deref())
+            .unwrap_or_else(|e| panic!("Failed to open {} reads: {}", what, e))
+    };
+
+    let indexer = Indexer {
+        ids: args.normalize_ids,
+        on_duplicate: args.on_duplicate,
+        unpaired: args.unpaired_policy,
+        ignore_truncation: args.ignore_truncation,
+    };
+    let (format, mut index) = if args.single || args.long_reads {
+        info!("\nCreating index for: {}", args.input.join(", "));
+        let (format, records) = open(&args.input, "single-end");
+        (format, indexer.single(records))
+    } else if args.interleaved {
+        info!("\nCreating index for interleaved: {}", args.input.join(", "));
+        let (format, records) = open(&args.input, "interleaved");
+        if matches!(format, SeqFormat::Bam | SeqFormat::Cram) {
+            // Aligned files are usually sorted by coordinate, which parts the mates
+            let (index, orphans) = indexer.by_name(records);
+            if !orphans.is_empty() {
+                warn!("{} reads have no mate in the input", orphans.len());
+            }
+            (format, index)
+        } else {
+            (format, indexer.interleaved(records))
+        }
+    } else {
+        info!("\nCreating index for: {} and {}", args.input.join(", "), args.input2.join(", "));
+        let (format, r1_records) = open(&args.input, "R1");
+        let (r2_format, r2_records) = open(&args.input2, "R2");
+        if format != r2_format {
+            eprintln!("R1 is {:?} but R2 is {:?}; both mates must use the same format", format, r2_format);
+            std::process::exit(1);
+        }
+        let (index, orphans) = indexer.paired(r1_records, r2_records);
+        if !orphans.is_empty() {
+            warn!("{} reads have no mate in the other mate file", orphans.len());
+        }
+        if let Some(output_orphans) = &args.output_orphans {
+            let mut out = RecordWriter::to_file(output_orphans, output_format(args.output_format, format), compression)
+                .expect("Failed to create orphan output file");
+            for orphan in &orphans {
+                out.write_record(orphan).expect("Failed to write orphan read");
+            }
+        }
+        (format, index)
+    };
+
+    if !args.singles.is_empty() {
+        info!("\nAdding unpaired reads from: {}", args.singles.join(", "));
+        let (singles_format, records) = open(&args.singles, "unpaired");
+        if singles_format != format {
+            eprintln!("Unpaired reads are {:?} but the pairs are {:?}", singles_format, format);
+            std::process::exit(1);
+        }
+        indexer.add_singles(&mut index, records);
+    }
+
+    let out_format = output_format(args.output_format, format);
+
+    let mut cluster_files = Vec::new();
+    for path in &args.cluster {
+        let reader = BufReader::new(open_input(path).expect("Could not open cluster file"));
+        cluster_files.push(
+            read_clusters(reader, args.cluster_format)
+                .unwrap_or_else(|e| panic!("Failed to read cluster file {}: {}", path, e)),
+        );
+    }
+    let clusters = if cluster_files.len() == 1 { cluster_files.remove(0) } else { merge_clusters(cluster_files) };
+    let mut writer = match (&args.output, args.sample_delimiter) {
+        (Some(output), None) => Some(open_writer(output, args.output2.as_deref(), out_format, compression)),
+        _ => None,
+    };
+    if let Some(output_singles) = &args.output_singles {
+        writer = writer.map(|writer| {
+            writer.with_singles(output_singles, out_format, compression).expect("Failed to create unpaired output file")
+        });
+    }
+    for (paths, output, name) in [(&args.i1, &args.output_i1, "I1"), (&args.i2, &args.output_i2, "I2")] {
+        if let Some(output) = output {
+            info!("\nCreating index for {} reads: {}", name, paths.join(", "));
+            let (index_format, records) = open(paths, name);
+            writer = writer.map(|writer| {
+                writer
+                    .with_index_reads(
+                        indexer.records(records, name),
+                        args.normalize_ids,
+                        output,
+                        output_format(args.output_format, index_format),
+                        compression,
+                    )
+                    .unwrap_or_else(|e| panic!("Failed to create {} output file: {}", name, e))
+            });
+        }
+    }
+
+    let mut selection = if args.long_reads { Selection::long_reads() } else { Selection::short_reads() };
+    selection.strategy = args.select;
+    selection.max_n = args.max_n;
+    selection.min_base_quality = args.min_base_quality;
+    selection.min_complexity = args.min_complexity;
+    selection.primers = args.primers.as_deref().map(read_primers);
+    if let Some(expression) = &args.score_expr {
+        if args.select != Strategy::Expression {
+            error!("--score-expr is only used with --select expression");
+            std::process::exit(1);
+        }
+        info!("Ranking members by {}", expression);
+        selection.expression = Some(expression.clone());
+    }
+    selection.score_trim = args.score_trim;
+    selection.score_mate = args.score_mate;
+    selection.low_quality = args.low_quality;
+    selection.prefer_aligned = args.prefer_aligned;
+    selection.vote_mismatches = args.vote_mismatches;
+    selection.vote_min_copies = args.vote_min_copies;
+    selection.vote_min_fraction = args.vote_min_fraction / 100.0;
+    selection.weights = Weights {
+        quality: args.weight_quality,
+        length: args.weight_length,
+        identity: args.weight_identity,
+        complexity: args.weight_complexity,
+    };
+    if let Some(score) = args.score {
+        selection.score = score;
+    }
+    let sample = index.values().take(QUALITY_SAMPLE).flat_map(|pair| pair.mates()).map(|r| r.qual());
+    let range = quality_range(sample);
+    selection.phred_offset = match format {
+        SeqFormat::Bam | SeqFormat::Cram if args.phred64 => {
+            warn!("Ignoring --phred64: qualities decoded from BAM/CRAM are always phred+33");
+            PHRED33
+        }
+        SeqFormat::Bam | SeqFormat::Cram => PHRED33,
+        _ if args.phred64 => PHRED64,
+        _ if args.phred33 => PHRED33,
+        _ => match range.map(detect_phred_offset) {
+            Some(PHRED64) => {
+                info!("\nDetected phred+64 qualities");
+                PHRED64
+            }
+            _ => PHRED33,
+        },
+    };
+    if let Some(range) = range
+        && let Err(e) = validate_quality_range(range, selection.phred_offset)
+    {
+        eprintln!("Invalid qualities in the input reads: {}", e);
+        std::process::exit(1);
+    }
+    if let Some(dir) = &args.write_msa {
+        std::fs::create_dir_all(dir).unwrap_or_else(|e| panic!("Failed to create MSA directory {}: {}", dir, e));
+    }
+    if args.output_consensus.is_some() && args.select.is_consensus() {
+        error!("--output-consensus writes a consensus next to the picks of a non-consensus --select");
+        std::process::exit(1);
+    }
+    let consensus = Consensus {
+        method: match args.select {
+            Strategy::Consensus => ConsensusMethod::Majority,
+            Strategy::ConsensusPoa => ConsensusMethod::Poa,
+            Strategy::ConsensusHomopolymer => ConsensusMethod::Homopolymer,
+            _ => args.consensus_method,
+        },
+        phred_offset: selection.phred_offset,
+        rounds: args.consensus_rounds,
+        weighted: args.weighted_vote,
+        iupac: args.iupac,
+    };
+    let merger = Merger {
+        min_overlap: args.merge_min_overlap,
+        max_mismatch_density: args.merge_max_mismatch / 100.0,
+        phred_offset: selection.phred_offset,
+    };
+    let mut plucker = Plucker {
+        index: &index,
+        ids: args.normalize_ids,
+        selection,
+        consensus,
+        msa_dir: args.write_msa.clone(),
+        identity_stats: args.identity_stats.as_deref().map(open_identity_stats),
+        variant_table: args.variant_table.as_deref().map(open_variant_table),
+        variant_min_frequency: args.variant_min_frequency,
+        consensus_min_size: args.consensus_min_size,
+        below_consensus_size: 0,
+        threads: args.select.is_consensus().then(|| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(args.threads.get())
+                .build()
+                .expect("Failed to start consensus threads")
+        }),
+        prebuilt: VecDeque::new(),
+        linked_consensus: args
+            .output_consensus
+            .as_deref()
+            .map(|output| open_writer(output, args.output_consensus2.as_deref(), out_format, compression)),
+        pool: Vec::new(),
+        progress: report(read_outputs(args).any(is_stdout)),
+        size_annotation: args.size_annotation,
+        tag_cluster: args.tag_cluster,
+        selection_table: args.selection_table.as_deref().map(open_selection_table),
+        scores: Vec::new(),
+        min_identity: args.min_identity,
+        orient: args.orient,
+        per_cluster: args.per_cluster.get(),
+        diverse: args.diverse,
+        umi: args.umi,
+        max_reads_per_cluster: args.max_reads_per_cluster.map(NonZeroUsize::get),
+        min_cluster_size: args.min_cluster_size,
+        skipped_small: 0,
+        max_cluster_size: args.max_cluster_size,
+        large: args.output_large.as_deref().map(|path| {
+            PairWriter::single(path, out_format, compression).expect("Failed to create large cluster output file")
+        }),
+        skipped_large: 0,
+        singletons: args
+            .output_singletons
+            .as_deref()
+            .map(|output| open_writer(output, args.output_singletons2.as_deref(), out_format, compression)),
+        singleton_count: 0,
+        min_rep_quality: args.min_rep_quality,
+        skipped_low_quality: 0,
+        mark_duplicates: args.mark_duplicates,
+        duplicates: args.output_duplicates.as_deref().map(|path| {
+            PairWriter::single(path, out_format, compression).expect("Failed to create duplicates output file")
+        }),
+        duplicate_count: 0,
+        discarded: args
+            .discarded_r1
+            .as_deref()
+            .map(|output| open_writer(output, args.discarded_r2.as_deref(), out_format, compression)),
+        discarded_count: 0,
+        merged: args.merge_output.as_deref().map(|path| {
+            RecordWriter::to_file(path, out_format, compression).expect("Failed to create merged output file")
+        }),
+        merger,
+        unmerged: 0,
+        rng: StdRng::seed_from_u64(args.seed),
+    };
+    let mut outputs = match (writer, &args.cluster_dir) {
+        (Some(writer), _) => Outputs::Pooled(writer),
+        (None, Some(dir)) => {
+            std::fs::create_dir_all(dir)
+                .unwrap_or_else(|e| panic!("Failed to create cluster directory {}: {}", dir, e));
+            Outputs::PerCluster(ClusterOutputs {
+                dir: dir.clone(),
+                split: !(args.single || args.long_reads || args.interleaved || args.interleave_output),
+                extension: read_extension(out_format, args.compress_output),
+                format: out_format,
+                compression,
+                written: 0,
+                names: HashSet::new(),
+            })
+        }
+        (None, None) => Outputs::PerSample(SampleOutputs {
+            delimiter: args.sample_delimiter.unwrap(),
+            output: args.output.clone().expect("--output is required"),
+            output2: args.output2.clone(),
+            format: out_format,
+            compression,
+            writers: HashMap::new(),
+        }),
+    };
+    let cluster_count = plucker.pluck_clusters(clusters, &mut outputs);
+
+    let mut summary = format!("\nProcessing complete. Clusters processed: {}\n", cluster_count);
+    if args.min_cluster_size > 1 {
+        summary +=
+            &format!("Clusters skipped (fewer than {} members): {}\n", args.min_cluster_size, plucker.skipped_small);
+    }
+    if let Some(max) = args.max_cluster_size {
+        summary += &format!("Clusters left out (more than {} members): {}\n", max, plucker.skipped_large);
+    }
+    if args.output_singletons.is_some() {
+        summary += &format!("Single-member clusters written separately: {}\n", plucker.singleton_count);
+    }
+    if args.select.is_consensus() && args.consensus_min_size > 1 {
+        summary += &format!(
+            "Clusters represented by their best read (fewer than {} members): {}\n",
+            args.consensus_min_size, plucker.below_consensus_size
+        );
+    }
+    if let Some(min) = args.min_rep_quality {
+        summary += &format!("Clusters dropped (best member below mean Q{}): {}\n", min, plucker.skipped_low_quality);
+    }
+    if args.merge_output.is_some() {
+        summary += &format!("Pairs not merged (no acceptable overlap): {}\n", plucker.unmerged);
+    }
+    if args.mark_duplicates || args.output_duplicates.is_some() {
+        summary += &format!("Duplicates kept: {}\n", plucker.duplicate_count);
+    }
+    if args.discarded_r1.is_some() {
+        summary += &format!("Pairs discarded: {}\n", plucker.discarded_count);
+    }
+    match &outputs {
+        Outputs::PerSample(samples) => summary += &format!("Samples written: {}\n", samples.writers.len()),
+        Outputs::PerCluster(clusters) => summary += &format!("Cluster files written: {}\n", clusters.written),
+        Outputs::Pooled(_) => {}
+    }
+    report(read_outputs(args).any(is_stdout)).write_all(summary.as_bytes()).expect("Failed to print summary");
+    RunSummary { reads_indexed: index.len(), clusters: cluster_count }
+}
+
+/// Number of cluster members whose qualities are inspected to detect and
+/// validate the quality encoding.
+const QUALITY_SAMPLE: usize = 10_000;
+
+/// Number of clusters whose consensus sequences are built together.
+const CONSENSUS_BATCH: usize = 1024;
+
+/// Read primer pairs from a FASTA listing the forward and reverse primer of
+/// each pair in turn.
+fn read_primers(path: &str) -> Primers {
+    let reader = open_input(path).unwrap_or_else(|e| panic!("Failed to open primers {}: {}", path, e));
+    let primers: Vec<Vec<u8>> = fasta::Reader::new(reader)
+        .records()
+        .map(|record| record.map(|record| record.seq().to_vec()))
+        .collect::<io::Result<_>>()
+        .unwrap_or_else(|e| panic!("Failed to read primers {}: {}", path, e));
+    if primers.is_empty() || !primers.len().is_multiple_of(2) {
+        error!("{} holds {} primers; expected forward and reverse primers in pairs", path, primers.len());
+        std::process::exit(1);
+    }
+    let pairs: Vec<_> = primers.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect();
+    info!("Read {} primer pairs from {}", pairs.len(), path);
+    Primers::new(pairs)
+}
+
+/// Write the member alignment of a consensus to `<dir>/<name>.fasta`, or to
+/// `<name>_R1.fasta` and `<name>_R2.fasta` for pairs.
+fn write_msa(dir: &str, name: &str, consensus: &ConsensusRead) {
+    let paired = consensus.pileups.len() > 1;
+    for (n, (pileup, mate)) in consensus.pileups.iter().zip(consensus.pair.mates()).enumerate() {
+        let file = if paired { format!("{}_R{}.fasta", name, n + 1) } else { format!("{}.fasta", name) };
+        let path = Path::new(dir).join(file);
+        File::create(&path)
+            .map(io::BufWriter::new)
+            .and_then(|mut out| pileup.write_fasta(name, mate.seq(), &mut out).and_then(|()| out.flush()))
+            .unwrap_or_else(|e| panic!("Failed to write alignment {}: {}", path.display(), e));
+    }
+}
+
+/// Create a --variant-table TSV and write its header.
+fn open_variant_table(path: &str) -> io::BufWriter<File> {
+    let mut out = File::create(path)
+        .map(io::BufWriter::new)
+        .unwrap_or_else(|e| panic!("Failed to create variant table {}: {}", path, e));
+    writeln!(out, "cluster\tmate\tposition\treference\talternative\tcount\tdepth\tfrequency")
+        .expect("Failed to write variant table");
+    out
+}
+
+/// Write the positions where at least `min_frequency` percent of the members
+/// of cluster `id`, aligned in `pileups`, disagree with `pair`.
+fn write_variants(out: &mut impl Write, id: &str, pileups: &[Pileup], pair: &PairedRecord, min_frequency: f64) {
+    for (n, (pileup, mate)) in pileups.iter().zip(pair.mates()).enumerate() {
+        for variant in pileup.variants(mate.seq(), min_frequency) {
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.2}",
+                id,
+                n + 1,
+                variant.position,
+                variant.reference as char,
+                variant.alternative as char,
+                variant.count,
+                variant.depth,
+                variant.frequency()
+            )
+            .expect("Failed to write variant table");
+        }
+    }
+}
+
+/// Create an --identity-stats TSV and write its header.
+fn open_identity_stats(path: &str) -> io::BufWriter<File> {
+    let mut out = File::create(path)
+        .map(io::BufWriter::new)
+        .unwrap_or_else(|e| panic!("Failed to create identity statistics {}: {}", path, e));
+    writeln!(out, "cluster\tmembers\tmean_identity\tmin_identity").expect("Failed to write identity statistics");
+    out
+}
+
+/// Create the selection table and write its header.
+fn open_selection_table(path: &str) -> io::BufWriter<File> {
+    let mut out = File::create(path)
+        .map(io::BufWriter::new)
+        .unwrap_or_else(|e| panic!("Failed to create selection table {}: {}", path, e));
+    writeln!(out, "cluster\tread\tsize\tscore").expect("Failed to write selection table");
+    out
+}
+
+/// Write the mean and lowest percent identity of the `members` of cluster
+/// `id` to their `consensus`.
+fn write_identity_stats(out: &mut impl Write, id: &str, members: &[&PairedRecord], consensus: &PairedRecord) {
+    let identities: Vec<f64> = members.iter().map(|member| identity(member, consensus)).collect();
+    let mean = identities.iter().sum::<f64>() / identities.len() as f64;
+    let min = identities.iter().copied().fold(f64::INFINITY, f64::min);
+    writeln!(out, "{}\t{}\t{:.2}\t{:.2}", id, identities.len(), mean, min)
+        .expect("Failed to write identity statistics");
+}
+
+/// Extension of the read files ClusterPluk names itself, e.g. `fastq.gz`.
+fn read_extension(format: OutputFormat, codec: Option<Codec>) -> String {
+    let extension = match format {
+        OutputFormat::Fastq => "fastq",
+        OutputFormat::Fasta => "fasta",
+    };
+    match codec {
+        Some(Codec::Gzip) => format!("{}.gz", extension),
+        Some(Codec::Bgzf) => format!("{}.bgz", extension),
+        Some(Codec::Zstd) => format!("{}.zst", extension),
+        None => extension.to_string(),
+    }
+}
+
+/// The format reads are written in: `requested`, or that of the `input`.
+/// Exits if FASTQ is requested for reads that have no qualities.
+fn output_format(requested: Option<OutputFormat>, input: SeqFormat) -> OutputFormat {
+    match requested {
+        Some(OutputFormat::Fastq) if input == SeqFormat::Fasta => {
+            error!("--output-format fastq needs reads with qualities, but the input is FASTA");
+            std::process::exit(1);
+        }
+        Some(format) => format,
+        None => input.into(),
+    }
+}
+
+/// Create the output files, one per mate or a single one.
+fn open_writer(
+    output: &str,
+    output2: Option<&str>,
+    format: OutputFormat,
+    compression: OutputCompression,
+) -> PairWriter {
+    match output2 {
+        Some(output2) => PairWriter::split(output, output2, format, compression),
+        None => PairWriter::single(output, format, compression),
+    }
+    .unwrap_or_else(|e| panic!("Failed to create output file {}: {}", output, e))
+}
+
+/// Where the picks of the written clusters go.
+enum Outputs {
+    Pooled(PairWriter),
+    PerSample(SampleOutputs),
+    PerCluster(ClusterOutputs),
+}
+
+/// Output files per sample, for clusters pooling the reads of several samples
+/// whose read IDs start with the sample name.
+struct SampleOutputs {
+    delimiter: char,
+    output: String,
+    output2: Option<String>,
+    format: OutputFormat,
+    compression: OutputCompression,
+    writers: HashMap<String, PairWriter>,
+}
+
+/// Name given to reads whose ID has no sample delimiter.
+const UNASSIGNED_SAMPLE: &str = "unassigned";
+
+impl SampleOutputs {
+    /// The members of `cluster` grouped by sample, in order of first
+    /// appearance, each group a cluster of its own with the same ID.
+    fn split(&self, cluster: &Cluster) -> Vec<(String, Cluster)> {
+        let mut samples: Vec<(String, Cluster)> = Vec::new();
+        for member in &cluster.members {
+            let sample = sample_prefix(&member.id, self.delimiter).unwrap_or(UNASSIGNED_SAMPLE);
+            match samples.iter_mut().find(|(name, _)| name == sample) {
+                Some((_, group)) => group.members.push(member.clone()),
+                None => samples
+                    .push((sample.to_string(), Cluster { id: cluster.id.clone(), members: vec![member.clone()] })),
+            }
+        }
+        samples
+    }
+
+    /// The writer of `sample`, creating its files on first use.
+    fn writer(&mut self, sample: &str) -> &mut PairWriter {
+        let SampleOutputs { output, output2, format, compression, writers, .. } = self;
+        writers.entry(sample.to_string()).or_insert_with(|| {
+            let output2 = output2.as_deref().map(|path| sample_path(path, sample));
+            open_writer(&sample_path(output, sample), output2.as_deref(), *format, *compression)
+        })
+    }
+}
+
+/// Output files per cluster, in one directory.
+struct ClusterOutputs {
+    dir: String,
+    /// Whether the mates go to separate files.
+    split: bool,
+    extension: String,
+    format: OutputFormat,
+    compression: OutputCompression,
+    /// Number of clusters given files.
+    written: usize,
+    /// File names given out so far.
+    names: HashSet<String>,
+}
+
+impl ClusterOutputs {
+    /// Create the files of the cluster with `id`. IDs taken from read names
+    /// can hold `/` and the like, so anything but ASCII letters, digits, `.`,
+    /// `_` and `-` is replaced by `_`; a name already given out gets the
+    /// cluster's ordinal appended.
+    fn writer(&mut self, id: &str) -> PairWriter {
+        let mut name = if !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()) {
+            format!("cluster_{:0>6}", id)
+        } else {
+            let id: String =
+                id.chars().map(|c| if c.is_ascii_alphanumeric() || ".-_".contains(c) { c } else { '_' }).collect();
+            format!("cluster_{}", id)
+        };
+        if !self.names.insert(name.clone()) {
+            name = format!("{}_{:06}", name, self.written);
+            warn!("Cluster {} shares its file name with an earlier cluster; writing it to {}", id, name);
+            self.names.insert(name.clone());
+        }
+        let path = |suffix: &str| {
+            Path::new(&self.dir).join(format!("{}{}.{}", name, suffix, self.extension)).to_string_lossy().into_owned()
+        };
+        self.written += 1;
+        if self.split {
+            open_writer(&path("_R1"), Some(&path("_R2")), self.format, self.compression)
+        } else {
+            open_writer(&path(""), None, self.format, self.compression)
+        }
+    }
+}
+
+/// The file name of `path` after `<prefix>_`, where a samplesheet run
+/// writes that output for the sample with out_prefix `prefix`.
+fn prefixed_path(prefix: &str, path: &str) -> String {
+    let name = Path::new(path).file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    format!("{}_{}", prefix, name)
+}
+
+/// `path` with its file name prefixed by `<sample>_`.
+fn sample_path(path: &str, sample: &str) -> String {
+    let path = Path::new(path);
+    let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!("{}_{}", sample, name)).to_string_lossy().into_owned()
+}
+
+struct Plucker<'a> {
+    index: &'a ReadIndex,
+    ids: IdNormalization,
+    selection: Selection,
+    /// How the consensus strategies combine the members.
+    consensus: Consensus,
+    /// Directory the member alignment of every consensus is written to.
+    msa_dir: Option<String>,
+    /// Where the identity of the members to every consensus is summarised.
+    identity_stats: Option<io::BufWriter<File>>,
+    /// Where the positions members disagree at are listed.
+    variant_table: Option<io::BufWriter<File>>,
+    /// Lowest percentage of disagreeing members for a position to be listed.
+    variant_min_frequency: f64,
+    /// Clusters listing fewer members get their best read instead of a
+    /// consensus.
+    consensus_min_size: usize,
+    /// Number of clusters given their best read for being too small.
+    below_consensus_size: usize,
+    /// Threads the consensus of a batch of clusters are built on, for the
+    /// consensus strategies.
+    threads: Option<rayon::ThreadPool>,
+    /// Consensus sequences built ahead for the clusters of the current
+    /// batch, in the order [`Plucker::consensus_of`] asks for them, with the
+    /// members they were built from.
+    prebuilt: VecDeque<(Vec<Cow<'a, PairedRecord>>, Option<ConsensusRead>)>,
+    /// Where the consensus written next to the picks goes, if anywhere.
+    linked_consensus: Option<PairWriter>,
+    /// The members the picks of the last cluster were made from, kept for
+    /// the linked consensus.
+    pool: Vec<Cow<'a, PairedRecord>>,
+    /// Where the cluster being processed is reported.
+    progress: Box<dyn Write>,
+    /// Append `;size=N` to the IDs of the picks.
+    size_annotation: bool,
+    /// Tag the picks with the ID of their cluster.
+    tag_cluster: bool,
+    /// Where every pick is listed with the value it was ranked by.
+    selection_table: Option<io::BufWriter<File>>,
+    /// The values the picks of the last cluster were ranked by, for the
+    /// selection table.
+    scores: Vec<Option<f64>>,
+    /// Leave out members whose percent identity to the representative is
+    /// recorded and below this.
+    min_identity: Option<f64>,
+    /// Reverse complement members aligned to the minus strand of their
+    /// representative.
+    orient: bool,
+    /// Number of pairs written per cluster.
+    per_cluster: usize,
+    /// Pick the pairs by farthest-point sampling instead of by rank.
+    diverse: bool,
+    /// Collapse members sharing a UMI in this format to their best one before
+    /// picking.
+    umi: Option<UmiFormat>,
+    /// Members of larger clusters are sampled down to this many before
+    /// selection.
+    max_reads_per_cluster: Option<usize>,
+    /// Clusters listing fewer members are skipped.
+    min_cluster_size: usize,
+    /// Number of clusters skipped for being too small.
+    skipped_small: usize,
+    /// Clusters listing more members are left out of the main output.
+    max_cluster_size: Option<usize>,
+    /// Where the picks of clusters over `max_cluster_size` go, if anywhere.
+    large: Option<PairWriter>,
+    /// Number of clusters left out for being too large.
+    skipped_large: usize,
+    /// Where the picks of single-member clusters go instead, if anywhere.
+    singletons: Option<PairWriter>,
+    /// Number of single-member clusters written there.
+    singleton_count: usize,
+    /// Clusters whose best pick has a lower mean quality are dropped.
+    min_rep_quality: Option<f64>,
+    /// Number of clusters dropped for their quality.
+    skipped_low_quality: usize,
+    /// Write the members not picked to the main output, tagged as duplicates.
+    mark_duplicates: bool,
+    /// Where the members not picked go otherwise, if anywhere.
+    duplicates: Option<PairWriter>,
+    /// Number of members marked or written as duplicates.
+    duplicate_count: usize,
+    /// Where every pair not picked goes, if anywhere.
+    discarded: Option<PairWriter>,
+    /// Number of pairs written as discarded.
+    discarded_count: usize,
+    /// Where the written pairs go merged, if anywhere.
+    merged: Option<RecordWriter>,
+    merger: Merger,
+    /// Number of written pairs that could not be merged.
+    unmerged: usize,
+    rng: StdRng,
+}
+
+impl<'a> Plucker<'a> {
+    /// Write the best pairs of every cluster. Members missing from the index
+    /// are reported and left out.
+    fn pluck_clusters(
+        &mut self,
+        clusters: impl Iterator<Item = io::Result<Cluster>>,
+        outputs: &mut Outputs,
+    ) -> usize {
+        let mut cluster_count = 0;
+        let batch_size = if self.threads.is_some() { CONSENSUS_BATCH } else { 1 };
+        let mut clusters = clusters.map(|cluster| cluster.expect("Failed to read cluster file"));
+
+        loop {
+            let batch: Vec<Cluster> = clusters.by_ref().take(batch_size).collect();
+            if batch.is_empty() {
+                break;
+            }
+            if self.threads.is_some() {
+                self.prebuild(&batch, outputs);
+            }
+            for cluster in batch {
+                cluster_count += 1;
+                self.pluck_cluster(cluster, cluster_count, outputs);
+            }
+        }
+
+        cluster_count
+    }
+
+    /// Write the picks of the `number`th cluster, unless it is skipped for
+    /// its size, to the singleton output if it has a single member.
+    fn pluck_cluster(&mut self, cluster: Cluster, number: usize, outputs: &mut Outputs) {
+        write!(self.progress, "\rProcessing cluster: {}", number).unwrap();
+        self.progress.flush().unwrap();
+
+        if cluster.members.len() < self.min_cluster_size {
+            self.skipped_small += 1;
+            self.discard(&cluster, &[]);
+            return;
+        }
+        if let Some(max) = self.max_cluster_size
+            && cluster.members.len() > max
+        {
+            self.skipped_large += 1;
+            let mut picked = Vec::new();
+            if let Some(mut large) = self.large.take() {
+                let (pairs, members) = self.picks(&cluster);
+                for pair in pairs {
+                    large.write_pair(&pair).expect("Failed to write large cluster output");
+                }
+                picked = members;
+                self.large = Some(large);
+            }
+            self.discard(&cluster, &picked);
+            return;
+        }
+        if cluster.members.len() == 1
+            && let Some(mut singletons) = self.singletons.take()
+        {
+            self.singleton_count += 1;
+            self.write_cluster(&cluster, &mut singletons);
+            self.singletons = Some(singletons);
+            return;
+        }
+        match outputs {
+            Outputs::Pooled(writer) => self.write_cluster(&cluster, writer),
+            Outputs::PerSample(samples) => {
+                for (sample, cluster) in samples.split(&cluster) {
+                    self.write_cluster(&cluster, samples.writer(&sample));
+                }
+            }
+            Outputs::PerCluster(clusters) => self.write_cluster(&cluster, &mut clusters.writer(&cluster.id)),
+        }
+    }
+
+    /// Build the consensus of every cluster of `batch` that
+    /// [`Plucker::pluck_cluster`] will ask for, on the consensus threads.
+    /// The members are gathered in cluster order first, so sampling them
+    /// does not depend on the number of threads.
+    fn prebuild(&mut self, batch: &[Cluster], outputs: &Outputs) {
+        let mut pools = Vec::new();
+        for cluster in batch {
+            if cluster.members.len() < self.min_cluster_size {
+                continue;
+            }
+            let large = self.max_cluster_size.is_some_and(|max| cluster.members.len() > max);
+            let units = match outputs {
+                _ if large && self.large.is_none() => Vec::new(),
+                _ if !large && cluster.members.len() == 1 && self.singletons.is_some() => vec![cluster.clone()],
+                Outputs::PerSample(samples) if !large => samples.split(cluster).into_iter().map(|(_, c)| c).collect(),
+                _ => vec![cluster.clone()],
+            };
+            for unit in &units {
+                if !self.wants_consensus(unit) {
+                    continue;
+                }
+                let members = self.members(unit);
+                let members = self.thin(members);
+                pools.push((format!("cluster{}", unit.id), members.into_iter().map(|(pair, _)| pair).collect()));
+            }
+        }
+        let consensus = &self.consensus;
+        let threads = self.threads.as_ref().expect("prebuilding without consensus threads");
+        let built: Vec<(Vec<Cow<'a, PairedRecord>>, Option<ConsensusRead>)> = threads.install(|| {
+            pools
+                .into_par_iter()
+                .map(|(name, pool): (String, Vec<Cow<'a, PairedRecord>>)| {
+                    let pairs: Vec<&PairedRecord> = pool.iter().map(|pair| pair.as_ref()).collect();
+                    let built = (!pairs.is_empty()).then(|| consensus.build(&name, &pairs));
+                    (pool, built)
+                })
+                .collect()
+        });
+        self.prebuilt.extend(built);
+    }
+
+    /// Whether `cluster` is represented by a consensus.
+    fn wants_consensus(&self, cluster: &Cluster) -> bool {
+        self.selection.strategy.is_consensus() && cluster.members.len() >= self.consensus_min_size
+    }
+
+    /// Write the picks of `cluster`, and its other members if they are kept,
+    /// unless even the best pick falls short of `min_rep_quality`.
+    fn write_cluster(&mut self, cluster: &Cluster, writer: &mut PairWriter) {
+        let (mut pairs, picked) = self.picks(cluster);
+        let pool = std::mem::take(&mut self.pool);
+        if let (Some(min), Some(best)) = (self.min_rep_quality, pairs.first())
+            && self.selection.mean_quality(best).is_some_and(|quality| quality < min)
+        {
+            self.skipped_low_quality += 1;
+            self.discard(cluster, &[]);
+            return;
+        }
+        if let Some(out) = &mut self.selection_table {
+            let size = cluster.members.len();
+            let scores = std::mem::take(&mut self.scores);
+            // A consensus stands for all members, so none is picked or scored
+            let rows: Vec<(&str, Option<f64>)> = if picked.is_empty() {
+                pairs.iter().map(|pair| (pair.id(), None)).collect()
+            } else {
+                picked.iter().zip(scores).map(|(member, score)| (member.id.as_str(), score)).collect()
+            };
+            for (id, score) in rows {
+                let score = score.map_or("NA".to_string(), |score| score.to_string());
+                writeln!(out, "{}\t{}\t{}\t{}", cluster.id, id, size, score).expect("Failed to write selection table");
+            }
+        }
+        if self.size_annotation {
+            let size = cluster.members.len();
+            pairs = pairs.iter().map(|pair| Cow::Owned(pair.with_size(size))).collect();
+        }
+        if self.tag_cluster {
+            let tag = format!("cluster={}", cluster.id);
+            pairs = pairs.iter().map(|pair| Cow::Owned(pair.with_description_tag(&tag))).collect();
+        }
+        if self.linked_consensus.is_some()
+            && let Some(best) = pairs.first()
+            && cluster.members.len() >= self.consensus_min_size
+        {
+            let pool: Vec<&PairedRecord> = pool.iter().map(|pair| pair.as_ref()).collect();
+            let mut consensus = self.build_consensus(cluster, &pool);
+            if self.size_annotation {
+                consensus = consensus.with_size(cluster.members.len());
+            }
+            let linked = consensus.with_description_tag(&format!("representative={}", best.id()));
+            let out = self.linked_consensus.as_mut().unwrap();
+            out.write_pair(&linked).expect("Failed to write consensus output");
+            let tag = format!("consensus={}", consensus.id());
+            pairs = pairs.iter().map(|pair| Cow::Owned(pair.with_description_tag(&tag))).collect();
+        }
+        // The picks may have been renamed, so their index reads are found by
+        // the IDs they were read with
+        let index = self.index;
+        let sources: Vec<&str> = picked
+            .iter()
+            .map(|member| index.get(self.ids.normalize(&member.id)).map_or(member.id.as_str(), |pair| pair.id()))
+            .collect();
+        for (i, pair) in pairs.iter().enumerate() {
+            let source = sources.get(i).copied().unwrap_or(pair.id());
+            writer.write_pair_from(pair, source).expect("Failed to write output");
+        }
+        if let Some(merged) = &mut self.merged {
+            for pair in &pairs {
+                match self.merger.merge(pair) {
+                    Some(contig) => merged.write_record(&contig).expect("Failed to write merged output"),
+                    None if pair.r2.is_some() => self.unmerged += 1,
+                    None => merged.write_record(&pair.r1).expect("Failed to write merged output"),
+                }
+            }
+        }
+        if self.mark_duplicates || self.duplicates.is_some() {
+            self.keep_duplicates(cluster, &picked, writer);
+        }
+        self.discard(cluster, &picked);
+    }
+
+    /// The pairs to write for `cluster` and the members they were picked
+    /// from: the plucked members, or a consensus standing for all of them
+    /// when the cluster is large enough.
+    fn picks<'c>(&mut self, cluster: &'c Cluster) -> (Vec<Cow<'a, PairedRecord>>, Vec<&'c Member>) {
+        if self.wants_consensus(cluster) {
+            return (self.consensus_of(cluster).into_iter().collect(), Vec::new());
+        }
+        if self.selection.strategy.is_consensus() {
+            self.below_consensus_size += 1;
+        }
+        self.pluck(cluster).into_iter().unzip()
+    }
+
+    /// Write the members of `cluster` that were not picked, as read, either
+    /// to `writer` tagged `DUP:cluster<ID>` or to the duplicates output.
+    fn keep_duplicates(&mut self, cluster: &Cluster, picked: &[&Member], writer: &mut PairWriter) {
+        let tag = format!("DUP:cluster{}", cluster.id);
+        for member in &cluster.members {
+            if picked.iter().any(|picked| std::ptr::eq(*picked, member)) {
+                continue;
+            }
+            // Missing members were already reported while plucking
+            let Some(pair) = self.index.get(self.ids.normalize(&member.id)) else { continue };
+            self.duplicate_count += 1;
+            match &mut self.duplicates {
+                Some(duplicates) => duplicates.write_pair(pair).expect("Failed to write duplicates output"),
+                None => writer.write_pair(&pair.with_description_tag(&tag)).expect("Failed to write output"),
+            }
+        }
+    }
+
+    /// Write the members of `cluster` that were not picked, as read, to the
+    /// discarded output if there is one.
+    fn discard(&mut self, cluster: &Cluster, picked: &[&Member]) {
+        let Some(discarded) = &mut self.discarded else { return };
+        for member in &cluster.members {
+            if picked.iter().any(|picked| std::ptr::eq(*picked, member)) {
+                continue;
+            }
+            let Some(pair) = self.index.get(self.ids.normalize(&member.id)) else { continue };
+            self.discarded_count += 1;
+            discarded.write_pair(pair).expect("Failed to write discarded output");
+        }
+    }
+
+    /// The best `per_cluster` pairs of `cluster`, best first, each picked by
+    /// the selection strategy from the members not picked yet; fewer if the
+    /// cluster runs out. With `diverse` the pairs after the first are spread
+    /// over the cluster instead. Reoriented and renamed members are copies;
+    /// all others are borrowed from the index. Each comes with its member.
+    fn pluck<'c>(&mut self, cluster: &'c Cluster) -> Vec<(Cow<'a, PairedRecord>, &'c Member)> {
+        let mut members = self.members(cluster);
+        let mut first = None;
+        if self.selection.strategy == Strategy::Representative
+            && let Some(position) = members.iter().position(|(_, member)| member.representative)
+        {
+            first = Some(members.remove(position));
+        }
+        members = self.thin(members);
+        // The members the picks are compared with for the variant table and
+        // the linked consensus
+        let everyone: Vec<Cow<'a, PairedRecord>> = match (&self.variant_table, &self.linked_consensus) {
+            (None, None) => Vec::new(),
+            _ => first.iter().chain(&members).map(|(pair, _)| pair.clone()).collect(),
+        };
+        let ranked: Vec<(Cow<'a, PairedRecord>, Option<f64>)> = match &self.selection_table {
+            Some(_) => first.iter().chain(&members).map(|(pair, member)| (pair.clone(), member.identity)).collect(),
+            None => Vec::new(),
+        };
+        let plucked = if self.diverse {
+            let first = match first {
+                Some(first) => first,
+                None if members.is_empty() => return Vec::new(),
+                None => {
+                    let position = self.select(&members);
+                    members.remove(position)
+                }
+            };
+            members.insert(0, first);
+            self.pluck_spread(members)
+        } else {
+            let mut plucked: Vec<_> = first.into_iter().collect();
+            // Every member may be missing, e.g. when the reads were truncated
+            while plucked.len() < self.per_cluster && !members.is_empty() {
+                let position = self.select(&members);
+                plucked.push(members.remove(position));
+            }
+            plucked
+        };
+        if let (Some(out), Some((best, _))) = (&mut self.variant_table, plucked.first()) {
+            let everyone: Vec<&PairedRecord> = everyone.iter().map(|pair| pair.as_ref()).collect();
+            write_variants(out, &cluster.id, &align_to(best, &everyone), best, self.variant_min_frequency);
+        }
+        if self.linked_consensus.is_some() {
+            self.pool = everyone;
+        }
+        if self.selection_table.is_some() {
+            let candidates: Vec<Candidate> =
+                ranked.iter().map(|(pair, identity)| Candidate { pair, identity: *identity }).collect();
+            let score = |(pair, member): &(Cow<PairedRecord>, &Member)| {
+                self.selection.score_of(&candidates, &Candidate { pair, identity: member.identity })
+            };
+            self.scores = plucked.iter().map(score).collect();
+        }
+        plucked
+    }
+
+    /// The consensus of the members of `cluster`, named `cluster<ID>`, or
+    /// `None` if every member is missing; the next prebuilt one if there is
+    /// one. The positions its members disagree at are listed if asked.
+    fn consensus_of(&mut self, cluster: &Cluster) -> Option<Cow<'a, PairedRecord>> {
+        let (pool, consensus) = match self.prebuilt.pop_front() {
+            Some(prebuilt) => prebuilt,
+            None => {
+                let members = self.members(cluster);
+                let pool: Vec<Cow<'a, PairedRecord>> = self.thin(members).into_iter().map(|(pair, _)| pair).collect();
+                let pairs: Vec<&PairedRecord> = pool.iter().map(|pair| pair.as_ref()).collect();
+                let name = format!("cluster{}", cluster.id);
+                let consensus = (!pairs.is_empty()).then(|| self.consensus.build(&name, &pairs));
+                (pool, consensus)
+            }
+        };
+        let consensus = consensus?;
+        let pairs: Vec<&PairedRecord> = pool.iter().map(|pair| pair.as_ref()).collect();
+        self.record_consensus(cluster, &pairs, &consensus);
+        if let Some(out) = &mut self.variant_table {
+            write_variants(out, &cluster.id, &consensus.pileups, &consensus.pair, self.variant_min_frequency);
+        }
+        Some(Cow::Owned(consensus.pair))
+    }
+
+    /// The consensus of some `members` of `cluster` (at least one), named
+    /// `cluster<ID>`, recorded like [`Plucker::consensus_of`] does.
+    fn build_consensus(&mut self, cluster: &Cluster, members: &[&PairedRecord]) -> PairedRecord {
+        let consensus = self.consensus.build(&format!("cluster{}", cluster.id), members);
+        self.record_consensus(cluster, members, &consensus);
+        consensus.pair
+    }
+
+    /// Write the alignment of a consensus to the `msa_dir` and its identity
+    /// statistics, if asked.
+    fn record_consensus(&mut self, cluster: &Cluster, members: &[&PairedRecord], consensus: &ConsensusRead) {
+        if let Some(dir) = &self.msa_dir {
+            write_msa(dir, consensus.pair.id(), consensus);
+        }
+        if let Some(out) = &mut self.identity_stats {
+            write_identity_stats(out, &cluster.id, members, &consensus.pair);
+        }
+    }
+
+    /// The members of `cluster` found in the index and passing
+    /// `min_identity`, reoriented if asked.
+    fn members<'c>(&self, cluster: &'c Cluster) -> Vec<(Cow<'a, PairedRecord>, &'c Member)> {
+        let mut members: Vec<(Cow<PairedRecord>, &Member)> = Vec::with_capacity(cluster.members.len());
+        for member in &cluster.members {
+            if let (Some(min), Some(identity)) = (self.min_identity, member.identity)
+                && identity < min
+            {
+                continue;
+            }
+            match self.index.get(self.ids.normalize(&member.id)) {
+                Some(pair) if self.orient && member.strand == Some(Strand::Reverse) => {
+                    members.push((Cow::Owned(pair.reverse_complement()), member))
+                }
+                Some(pair) => members.push((Cow::Borrowed(pair), member)),
+                None => error!("Read ID {} not found in FASTQ files", member.id),
+            }
+        }
+        members
+    }
+
+    /// Collapse UMI groups and sample huge clusters down, as asked.
+    fn thin<'m>(
+        &mut self,
+        mut members: Vec<(Cow<'a, PairedRecord>, &'m Member)>,
+    ) -> Vec<(Cow<'a, PairedRecord>, &'m Member)> {
+        if let Some(umi) = self.umi {
+            members = self.collapse_umis(members, umi);
+        }
+        if let Some(max) = self.max_reads_per_cluster
+            && members.len() > max
+        {
+            members = members.into_iter().choose_multiple(&mut self.rng, max);
+        }
+        members
+    }
+
+    /// Up to `per_cluster` pairs spanning the diversity of `members`, starting
+    /// from the first, renamed with a `_repN` suffix in the order picked.
+    fn pluck_spread<'m>(
+        &self,
+        members: Vec<(Cow<'a, PairedRecord>, &'m Member)>,
+    ) -> Vec<(Cow<'a, PairedRecord>, &'m Member)> {
+        let pairs: Vec<&PairedRecord> = members.iter().map(|(pair, _)| pair.as_ref()).collect();
+        let order = farthest_points(&pairs, self.per_cluster);
+        let mut members: Vec<_> = members.into_iter().map(Some).collect();
+        order
+            .into_iter()
+            .enumerate()
+            .map(|(n, position)| {
+                let (pair, member) = members[position].take().unwrap();
+                (Cow::Owned(pair.with_id_suffix(&format!("_rep{}", n + 1))), member)
+            })
+            .collect()
+    }
+
+    /// Position of the member the selection strategy picks from `members`.
+    fn select(&self, members: &[(Cow<PairedRecord>, &Member)]) -> usize {
+        let candidates: Vec<Candidate> =
+            members.iter().map(|(pair, member)| Candidate { pair, identity: member.identity }).collect();
+        let best = self.selection.pluck(&candidates);
+        candidates.iter().position(|candidate| std::ptr::eq(candidate.pair, best)).unwrap()
+    }
+
+    /// Replace every group of members sharing a UMI, which are PCR duplicates
+    /// of one molecule, by the member the selection strategy picks from it.
+    /// Members without a UMI are kept as they are.
+    fn collapse_umis<'m>(
+        &self,
+        members: Vec<(Cow<'a, PairedRecord>, &'m Member)>,
+        umi: UmiFormat,
+    ) -> Vec<(Cow<'a, PairedRecord>, &'m Member)> {
+        let mut groups: Vec<Vec<_>> = Vec::new();
+        let mut group_of_umi: HashMap<String, usize> = HashMap::new();
+        for (pair, member) in members {
+            match umi.umi(pair.r1.id(), pair.r1.desc()) {
+                Some(key) => {
+                    let group = *group_of_umi.entry(key.to_string()).or_insert_with(|| {
+                        groups.push(Vec::new());
+                        groups.len() - 1
+                    });
+                    groups[group].push((pair, member));
+                }
+                None => groups.push(vec![(pair, member)]),
+            }
+        }
+        groups
+            .into_iter()
+            .map(|mut group| {
+                let position = self.select(&group);
+                group.swap_remove(position)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,83 @@
+//! Overlap merging of read pairs into single contigs, in the manner of FLASH:
+//! the end of R1 is overlapped with the start of the reverse complement of R2.
+
+use bio::alphabets::dna;
+use bio::io::fastq;
+use crate::record::PairedRecord;
+use crate::select::PHRED33;
+
+/// Lowest phred quality given to a merged base the mates disagree on.
+const MIN_MISMATCH_QUALITY: u8 = 2;
+
+/// Merges the mates of a pair where they overlap.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Merger {
+    /// Fewest overlapping bases accepted.
+    pub min_overlap: usize,
+    /// Highest fraction of mismatching bases accepted in the overlap.
+    pub max_mismatch_density: f64,
+    /// ASCII offset of the qualities.
+    pub phred_offset: u8,
+}
+
+impl Default for Merger {
+    fn default() -> Self {
+        Merger { min_overlap: 10, max_mismatch_density: 0.25, phred_offset: PHRED33 }
+    }
+}
+
+impl Merger {
+    /// The contig of a pair whose mates overlap, named after R1, or `None`
+    /// for single reads and pairs without an acceptable overlap. Of the
+    /// overlaps of at least `min_overlap` bases, the one with the lowest
+    /// mismatch density wins, ties going to the longest. Where the mates
+    /// agree the higher quality is kept; where they disagree the
+    /// higher-quality base is taken with the difference of the qualities.
+    /// Only the end of R1 is overlapped with the start of R2, so pairs whose
+    /// reads run past each other (inserts shorter than a read) do not merge.
+    pub fn merge(&self, pair: &PairedRecord) -> Option<fastq::Record> {
+        let r1 = &pair.r1;
+        let r2 = pair.r2.as_ref()?;
+        let (s1, q1) = (r1.seq().to_ascii_uppercase(), r1.qual());
+        let s2 = dna::revcomp(r2.seq().to_ascii_uppercase());
+        let q2: Vec<u8> = r2.qual().iter().rev().copied().collect();
+
+        // (mismatch density, overlap length) of the best overlap so far
+        let mut best: Option<(f64, usize)> = None;
+        for overlap in (self.min_overlap.max(1)..=s1.len().min(s2.len())).rev() {
+            let start = s1.len() - overlap;
+            let mismatches = s1[start..].iter().zip(&s2[..overlap]).filter(|(a, b)| a != b).count();
+            let density = mismatches as f64 / overlap as f64;
+            if density <= self.max_mismatch_density && best.is_none_or(|(lowest, _)| density < lowest) {
+                best = Some((density, overlap));
+            }
+        }
+        let (_, overlap) = best?;
+
+        let start = s1.len() - overlap;
+        let has_qual = !q1.is_empty() && !q2.is_empty();
+        let mut seq = s1[..start].to_vec();
+        let mut qual = if has_qual { q1[..start].to_vec() } else { Vec::new() };
+        for i in 0..overlap {
+            let (a, b) = (s1[start + i], s2[i]);
+            if !has_qual {
+                seq.push(a);
+                continue;
+            }
+            let (qa, qb) = (q1[start + i], q2[i]);
+            if a == b {
+                seq.push(a);
+                qual.push(qa.max(qb));
+            } else {
+                let (base, high, low) = if qa >= qb { (a, qa, qb) } else { (b, qb, qa) };
+                seq.push(base);
+                qual.push((high - low).max(MIN_MISMATCH_QUALITY) + self.phred_offset);
+            }
+        }
+        seq.extend_from_slice(&s2[overlap..]);
+        if has_qual {
+            qual.extend_from_slice(&q2[overlap..]);
+        }
+        Some(fastq::Record::with_attrs(r1.id(), r1.desc(), &seq, &qual))
+    }
+}
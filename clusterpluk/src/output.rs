@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use log::warn;
+use bio::io::{fasta, fastq};
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use noodles::bgzf::{self, io::writer::CompressionLevel};
+use crate::ids::IdNormalization;
+use crate::record::{PairedRecord, SeqFormat};
+
+/// Formats reads can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Fastq,
+    Fasta,
+}
+
+impl From<SeqFormat> for OutputFormat {
+    /// Keep FASTA as FASTA; everything carrying qualities becomes FASTQ.
+    fn from(format: SeqFormat) -> Self {
+        match format {
+            SeqFormat::Fasta => OutputFormat::Fasta,
+            SeqFormat::Fastq | SeqFormat::Bam | SeqFormat::Cram => OutputFormat::Fastq,
+        }
+    }
+}
+
+/// Path that writes to standard output instead of a file.
+pub const STDOUT_PATH: &str = "-";
+
+/// Whether `path` refers to standard output, either as `-` or `/dev/stdout`.
+pub fn is_stdout(path: &str) -> bool {
+    path == STDOUT_PATH || path == "/dev/stdout"
+}
+
+/// Compressed formats reads can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Codec {
+    Gzip,
+    /// Blocked gzip, as used for BAM and tabix; still readable as gzip.
+    Bgzf,
+    Zstd,
+}
+
+impl Codec {
+    /// The codec named by the extension of `path`: `.gz`, `.bgz` or `.zst`.
+    fn from_path(path: &str) -> Option<Codec> {
+        match path.rsplit_once('.')?.1 {
+            "gz" => Some(Codec::Gzip),
+            "bgz" | "bgzf" => Some(Codec::Bgzf),
+            "zst" => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Compression level used when none is given.
+    fn default_level(self) -> u32 {
+        match self {
+            Codec::Gzip | Codec::Bgzf => 6,
+            Codec::Zstd => 3,
+        }
+    }
+}
+
+/// How output files are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OutputCompression {
+    /// Codec of the outputs whose extension names none, and of `.gz`
+    /// outputs when this is bgzf.
+    pub codec: Option<Codec>,
+    /// Compression level, capped at 9 for gzip and bgzf; [`Codec`]'s own
+    /// default when `None`.
+    pub level: Option<u32>,
+}
+
+impl OutputCompression {
+    /// Create `path`, wrapped in the encoder its extension or `codec` asks
+    /// for. A path of `-` writes to standard output, compressed only if
+    /// `codec` is set.
+    pub fn create(&self, path: &str) -> io::Result<Box<dyn Write>> {
+        let file: Box<dyn Write> = if is_stdout(path) { Box::new(io::stdout()) } else { Box::new(File::create(path)?) };
+        let codec = match (Codec::from_path(path), self.codec) {
+            (Some(Codec::Gzip), Some(Codec::Bgzf)) => Some(Codec::Bgzf),
+            (Some(codec), _) => Some(codec),
+            (None, codec) => codec,
+        };
+        let Some(codec) = codec else {
+            return Ok(file);
+        };
+        let level = self.level.unwrap_or(codec.default_level());
+        Ok(match codec {
+            Codec::Gzip => Box::new(GzEncoder::new(file, flate2::Compression::new(level.min(9)))),
+            Codec::Bgzf => {
+                let level = CompressionLevel::try_from(level.min(9) as u8)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                Box::new(bgzf::io::writer::Builder::default().set_compression_level(level).build_from_writer(file))
+            }
+            Codec::Zstd => Box::new(zstd::stream::write::Encoder::new(file, level as i32)?.auto_finish()),
+        })
+    }
+}
+
+/// Writes reads to one file in FASTQ or FASTA format.
+pub enum RecordWriter {
+    Fastq(fastq::Writer<Box<dyn Write>>),
+    Fasta(fasta::Writer<Box<dyn Write>>),
+}
+
+impl RecordWriter {
+    pub fn to_file(path: &str, format: OutputFormat, compression: OutputCompression) -> io::Result<Self> {
+        let file = compression.create(path)?;
+        match format {
+            OutputFormat::Fastq => Ok(RecordWriter::Fastq(fastq::Writer::new(file))),
+            OutputFormat::Fasta => Ok(RecordWriter::Fasta(fasta::Writer::new(file))),
+        }
+    }
+
+    pub fn write_record(&mut self, record: &fastq::Record) -> io::Result<()> {
+        match self {
+            RecordWriter::Fastq(writer) => writer.write_record(record),
+            RecordWriter::Fasta(writer) => writer.write(record.id(), record.desc(), record.seq()),
+        }
+    }
+}
+
+/// How selected pairs are laid out over the output files.
+enum Layout {
+    /// One file per mate.
+    Split(RecordWriter, RecordWriter),
+    /// A single file holding single-end reads or interleaved pairs.
+    Single(RecordWriter),
+}
+
+/// Index reads keyed by normalised ID, and where to write them.
+struct IndexReads {
+    reads: HashMap<String, fastq::Record>,
+    ids: IdNormalization,
+    out: RecordWriter,
+}
+
+/// Destination for selected reads, with an optional separate file for
+/// single-end members (unpaired singles) of paired clusters.
+///
+/// Index reads (I1/I2) added with [`PairWriter::with_index_reads`] are written
+/// alongside every selected pair so the outputs stay in sync for
+/// demultiplexing.
+pub struct PairWriter {
+    layout: Layout,
+    singles: Option<RecordWriter>,
+    index_reads: Vec<IndexReads>,
+}
+
+impl PairWriter {
+    /// Write R1 and R2 to separate files.
+    pub fn split(
+        r1_output: &str,
+        r2_output: &str,
+        format: OutputFormat,
+        compression: OutputCompression,
+    ) -> io::Result<Self> {
+        let layout = Layout::Split(
+            RecordWriter::to_file(r1_output, format, compression)?,
+            RecordWriter::to_file(r2_output, format, compression)?,
+        );
+        Ok(PairWriter { layout, singles: None, index_reads: Vec::new() })
+    }
+
+    /// Write every mate, in order, to a single file.
+    pub fn single(output: &str, format: OutputFormat, compression: OutputCompression) -> io::Result<Self> {
+        let layout = Layout::Single(RecordWriter::to_file(output, format, compression)?);
+        Ok(PairWriter { layout, singles: None, index_reads: Vec::new() })
+    }
+
+    /// Send selected members without a mate to `output`.
+    pub fn with_singles(
+        mut self,
+        output: &str,
+        format: OutputFormat,
+        compression: OutputCompression,
+    ) -> io::Result<Self> {
+        self.singles = Some(RecordWriter::to_file(output, format, compression)?);
+        Ok(self)
+    }
+
+    /// Write the index read matching every selected member to `output`. The
+    /// member's ID is normalised with `ids` and looked up in `reads`.
+    pub fn with_index_reads(
+        mut self,
+        reads: HashMap<String, fastq::Record>,
+        ids: IdNormalization,
+        output: &str,
+        format: OutputFormat,
+        compression: OutputCompression,
+    ) -> io::Result<Self> {
+        let out = RecordWriter::to_file(output, format, compression)?;
+        self.index_reads.push(IndexReads { reads, ids, out });
+        Ok(self)
+    }
+
+    pub fn write_pair(&mut self, pair: &PairedRecord) -> io::Result<()> {
+        self.write_pair_from(pair, pair.id())
+    }
+
+    /// Write `pair`, made from the read with ID `source`, e.g. before a
+    /// `;size=` annotation or `_rep` suffix was added to its ID. The index
+    /// reads of `source` are written, renamed to the ID of `pair` if it
+    /// differs.
+    pub fn write_pair_from(&mut self, pair: &PairedRecord, source: &str) -> io::Result<()> {
+        for IndexReads { reads, ids, out } in &mut self.index_reads {
+            match reads.get(ids.normalize(source)) {
+                Some(read) if pair.id() == source => out.write_record(read)?,
+                Some(read) => {
+                    out.write_record(&fastq::Record::with_attrs(pair.id(), read.desc(), read.seq(), read.qual()))?
+                }
+                None => warn!("No index read found for {}", source),
+            }
+        }
+        if let (None, Some(out)) = (&pair.r2, &mut self.singles) {
+            return out.write_record(&pair.r1);
+        }
+        match &mut self.layout {
+            Layout::Split(out1, out2) => match &pair.r2 {
+                Some(r2) => {
+                    out1.write_record(&pair.r1)?;
+                    out2.write_record(r2)
+                }
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Read {} has no R2 mate to write", pair.id()),
+                )),
+            },
+            Layout::Single(out) => pair.mates().try_for_each(|r| out.write_record(r)),
+        }
+    }
+}
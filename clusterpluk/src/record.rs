@@ -0,0 +1,107 @@
+use std::borrow::Cow;
+use bio::alphabets::dna;
+use bio::io::fastq;
+use clap::ValueEnum;
+
+/// Sequence file formats supported for reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SeqFormat {
+    Fastq,
+    Fasta,
+    /// Unaligned BAM
+    Bam,
+    /// CRAM, decoded against --reference when reads were aligned
+    Cram,
+}
+
+/// A cluster member: an R1 read and its R2 mate, which is absent for
+/// single-end data.
+#[derive(Debug, Clone)]
+pub struct PairedRecord {
+    pub r1: fastq::Record,
+    pub r2: Option<fastq::Record>,
+}
+
+impl PairedRecord {
+    pub fn single(r1: fastq::Record) -> Self {
+        PairedRecord { r1, r2: None }
+    }
+
+    pub fn paired(r1: fastq::Record, r2: fastq::Record) -> Self {
+        PairedRecord { r1, r2: Some(r2) }
+    }
+
+    pub fn id(&self) -> &str {
+        self.r1.id()
+    }
+
+    /// The mates of this member in output order.
+    pub fn mates(&self) -> impl Iterator<Item = &fastq::Record> {
+        std::iter::once(&self.r1).chain(self.r2.as_ref())
+    }
+
+    /// Sequence of all mates concatenated, R1 first. Borrowed for single-end
+    /// reads so long reads are not copied.
+    pub fn seq(&self) -> Cow<'_, [u8]> {
+        match &self.r2 {
+            None => Cow::Borrowed(self.r1.seq()),
+            Some(r2) => Cow::Owned([self.r1.seq(), r2.seq()].concat()),
+        }
+    }
+
+    /// Whether the member carries base qualities; FASTA input has none.
+    pub fn has_qual(&self) -> bool {
+        self.mates().all(|r| !r.qual().is_empty())
+    }
+
+    /// Qualities of all mates concatenated, R1 first. Borrowed for single-end
+    /// reads.
+    pub fn qual(&self) -> Cow<'_, [u8]> {
+        match &self.r2 {
+            None => Cow::Borrowed(self.r1.qual()),
+            Some(r2) => Cow::Owned([self.r1.qual(), r2.qual()].concat()),
+        }
+    }
+
+    /// A copy of this member with `suffix` appended to the ID of every mate.
+    pub fn with_id_suffix(&self, suffix: &str) -> Self {
+        let rename = |r: &fastq::Record| {
+            fastq::Record::with_attrs(&format!("{}{}", r.id(), suffix), r.desc(), r.seq(), r.qual())
+        };
+        PairedRecord { r1: rename(&self.r1), r2: self.r2.as_ref().map(rename) }
+    }
+
+    /// A copy of this member whose mates' IDs end in the USEARCH-style
+    /// abundance annotation `;size=<size>`, replacing any they carried.
+    pub fn with_size(&self, size: usize) -> Self {
+        let annotate = |r: &fastq::Record| {
+            let fields: Vec<&str> = r.id().split(';').filter(|field| !field.starts_with("size=")).collect();
+            let id = format!("{};size={}", fields.join(";").trim_end_matches(';'), size);
+            fastq::Record::with_attrs(&id, r.desc(), r.seq(), r.qual())
+        };
+        PairedRecord { r1: annotate(&self.r1), r2: self.r2.as_ref().map(annotate) }
+    }
+
+    /// A copy of this member with `tag` appended to the description of every
+    /// mate.
+    pub fn with_description_tag(&self, tag: &str) -> Self {
+        let tagged = |r: &fastq::Record| {
+            let desc = match r.desc() {
+                Some(desc) => format!("{} {}", desc, tag),
+                None => tag.to_string(),
+            };
+            fastq::Record::with_attrs(r.id(), Some(&desc), r.seq(), r.qual())
+        };
+        PairedRecord { r1: tagged(&self.r1), r2: self.r2.as_ref().map(tagged) }
+    }
+
+    /// This member as read from the opposite strand: every mate reverse
+    /// complemented, with its qualities reversed to match.
+    pub fn reverse_complement(&self) -> Self {
+        let flip = |r: &fastq::Record| {
+            let qual: Vec<u8> = r.qual().iter().rev().copied().collect();
+            fastq::Record::with_attrs(r.id(), r.desc(), &dna::revcomp(r.seq()), &qual)
+        };
+        PairedRecord { r1: flip(&self.r1), r2: self.r2.as_ref().map(flip) }
+    }
+}
@@ -0,0 +1,45 @@
+use std::io::{self, Read};
+
+/// Whether `path` is a URL to stream rather than a local file.
+pub fn is_remote(path: &str) -> bool {
+    ["http://", "https://", "s3://"].iter().any(|scheme| path.starts_with(scheme))
+}
+
+/// Stream the object at an http(s):// or s3:// URL.
+///
+/// `s3://bucket/key` is fetched over HTTPS from the bucket's virtual-hosted
+/// endpoint, in `AWS_REGION` when set or at `AWS_ENDPOINT_URL` for
+/// S3-compatible stores. Requests are unsigned, so private objects need a
+/// presigned https:// URL instead.
+#[cfg(feature = "remote")]
+pub fn open_remote(url: &str) -> io::Result<Box<dyn Read>> {
+    let url = match url.strip_prefix("s3://") {
+        Some(object) => s3_https_url(object)?,
+        None => url.to_string(),
+    };
+    let response = ureq::get(&url).call().map_err(|e| io::Error::other(format!("{}: {}", url, e)))?;
+    Ok(Box::new(response.into_body().into_reader()))
+}
+
+#[cfg(not(feature = "remote"))]
+pub fn open_remote(url: &str) -> io::Result<Box<dyn Read>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Cannot open {}: built without the `remote` feature", url),
+    ))
+}
+
+#[cfg(feature = "remote")]
+fn s3_https_url(object: &str) -> io::Result<String> {
+    let (bucket, key) = object
+        .split_once('/')
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("s3://{} has no key", object)))?;
+
+    if let Ok(endpoint) = std::env::var("AWS_ENDPOINT_URL") {
+        return Ok(format!("{}/{}/{}", endpoint.trim_end_matches('/'), bucket, key));
+    }
+    match std::env::var("AWS_REGION") {
+        Ok(region) => Ok(format!("https://{}.s3.{}.amazonaws.com/{}", bucket, region, key)),
+        Err(_) => Ok(format!("https://{}.s3.amazonaws.com/{}", bucket, key)),
+    }
+}
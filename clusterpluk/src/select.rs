@@ -0,0 +1,1015 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use bio::alignment::distance::{hamming, levenshtein};
+use bio::alphabets::dna;
+use bio::io::fastq;
+use clap::ValueEnum;
+use log::debug;
+use crate::alignment::Alignment;
+use crate::expr::{Expr, Variables};
+use crate::record::PairedRecord;
+
+/// Per-member quality score used to rank cluster members. Scores are error
+/// probabilities or counts, so lower is better.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum QualityScore {
+    /// Mean per-base error probability over all mates
+    MeanError,
+    /// Error probability of the median base quality, which is not biased by read length and
+    /// suits long reads
+    MedianQuality,
+    /// Total expected number of errors over all mates: the sum of the per-base error
+    /// probabilities, as used by USEARCH's maxee filters
+    ExpectedErrors,
+    /// Number of bases below the low-quality threshold (Q20 by default), ambiguous bases
+    /// included; robust to a few very bad bases dominating the mean
+    LowQualityBases,
+}
+
+impl QualityScore {
+    /// Score ASCII-encoded qualities stored with the given phred offset, along
+    /// with the bases they belong to. Ambiguous bases count as at least as
+    /// likely to be wrong as their IUPAC code implies, whatever their quality.
+    /// `low_quality` is the phred threshold of [`QualityScore::LowQualityBases`].
+    fn score(self, seq: &[u8], qual: &[u8], offset: u8, low_quality: u8) -> f64 {
+        match self {
+            QualityScore::MeanError => expected_errors(seq, qual, offset) / qual.len() as f64,
+            QualityScore::ExpectedErrors => expected_errors(seq, qual, offset),
+            QualityScore::MedianQuality => {
+                // Ambiguous bases rank as Q0 so a run of Ns cannot lift the median
+                let qual: Vec<u8> = seq
+                    .iter()
+                    .zip(qual)
+                    .map(|(&b, &q)| if ambiguity_error(b) > 0.0 { offset } else { q })
+                    .collect();
+                phred_to_error(median(&qual), offset)
+            }
+            QualityScore::LowQualityBases => seq
+                .iter()
+                .zip(qual)
+                .filter(|&(&b, &q)| q.saturating_sub(offset) < low_quality || ambiguity_error(b) > 0.0)
+                .count() as f64,
+        }
+    }
+}
+
+/// Sum of the per-base error probabilities, each at least the ambiguity of
+/// its IUPAC code.
+fn expected_errors(seq: &[u8], qual: &[u8], offset: u8) -> f64 {
+    seq.iter().zip(qual).map(|(&b, &q)| phred_to_error(q, offset).max(ambiguity_error(b))).sum()
+}
+
+/// Probability that the true base differs from a call of `base` given only
+/// its IUPAC code: 0 for A, C, G, T (and U), 1/2 for two-base codes, 2/3 for
+/// three-base codes and 3/4 for N. Unknown symbols count as N.
+fn ambiguity_error(base: u8) -> f64 {
+    match base.to_ascii_uppercase() {
+        b'A' | b'C' | b'G' | b'T' | b'U' => 0.0,
+        b'R' | b'Y' | b'S' | b'W' | b'K' | b'M' => 0.5,
+        b'B' | b'D' | b'H' | b'V' => 2.0 / 3.0,
+        _ => 0.75,
+    }
+}
+
+/// Sequence compared in the exact-sequence vote. Case is ignored, so
+/// soft-masked copies of a sequence count as the same sequence.
+fn vote_key(seq: &[u8]) -> Vec<u8> {
+    seq.to_ascii_uppercase()
+}
+
+/// Standard Sanger/Illumina 1.8+ quality offset.
+pub const PHRED33: u8 = 33;
+
+/// Offset used by Illumina 1.3-1.7 (e.g. GAIIx) data.
+pub const PHRED64: u8 = 64;
+
+/// Highest printable quality character; anything above is not a valid quality.
+const MAX_QUAL: u8 = b'~';
+
+/// Lowest and highest quality byte over `quals`, or `None` if they are all
+/// empty (FASTA input).
+pub fn quality_range<'a>(quals: impl IntoIterator<Item = &'a [u8]>) -> Option<(u8, u8)> {
+    quals.into_iter().flatten().fold(None, |range, &q| match range {
+        None => Some((q, q)),
+        Some((min, max)) => Some((min.min(q), max.max(q))),
+    })
+}
+
+/// Infer the phred offset from the quality range of a sample of reads.
+/// Phred+33 data practically always has some base below Q31 (`@`), while
+/// phred+64 data reaches above Q10 (`J`), so only a sample that does both is
+/// taken to be phred+64.
+pub fn detect_phred_offset((min, max): (u8, u8)) -> u8 {
+    if min >= PHRED64 && max > b'J' { PHRED64 } else { PHRED33 }
+}
+
+/// Check that a quality range is possible with the given phred offset.
+pub fn validate_quality_range((min, max): (u8, u8), offset: u8) -> Result<(), String> {
+    if min < offset {
+        Err(format!("quality {:?} is below the phred+{} range", min as char, offset))
+    } else if max > MAX_QUAL {
+        Err(format!("quality byte {} is not a printable quality character", max))
+    } else {
+        Ok(())
+    }
+}
+
+fn phred_to_error(q: u8, offset: u8) -> f64 {
+    10f64.powf(-(q as f64 - offset as f64) / 10.0)
+}
+
+/// Median of quality bytes, counted in a histogram so very long reads need no
+/// sorted copy.
+fn median(qual: &[u8]) -> u8 {
+    let mut histogram = [0usize; 256];
+    for &q in qual {
+        histogram[q as usize] += 1;
+    }
+    let mut seen = 0;
+    for (q, &count) in histogram.iter().enumerate() {
+        seen += count;
+        if seen * 2 >= qual.len() {
+            return q as u8;
+        }
+    }
+    0
+}
+
+/// Strategy for choosing the member written for each cluster.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Strategy {
+    /// The member with the best base qualities among those carrying the most abundant sequence
+    /// (the only criterion for --long-reads)
+    #[default]
+    BestQuality,
+    /// The most abundant sequence, ties going to the longest, ignoring qualities
+    MostAbundant,
+    /// The longest member, R1 and R2 lengths combined, ties going to the best base qualities.
+    /// Suits length-variable amplicons, where ranking by quality favours short reads
+    Longest,
+    /// The member with the smallest total edit distance to the other members, ties going to the
+    /// best base qualities. Clusters over 100 members are sampled
+    Medoid,
+    /// A member of the median length, ties going to the best base qualities. Keeps length
+    /// outliers such as adapter read-through and truncated reads out of amplicon output
+    MedianLength,
+    /// The member whose GC content and length are closest to the cluster mean, for data where
+    /// qualities are uninformative, e.g. already quality filtered
+    Profile,
+    /// The member with the highest weighted sum of base quality, length and identity to the
+    /// representative (see --weight-quality, --weight-length and --weight-identity)
+    Weighted,
+    /// The member for which --score-expr evaluates highest
+    Expression,
+    /// The member scored highest by a caller-supplied function; library use only, see
+    /// [`Selection::with_scorer`]
+    #[value(skip)]
+    Custom,
+    /// The member the clustering tool chose as representative
+    Representative,
+    /// A per-position majority consensus of the members, written as a new record named after
+    /// the cluster. Members of other lengths than the most common one are left out
+    Consensus,
+    /// A consensus from a partial-order alignment of all members, which unlike the majority
+    /// copes with indels; suits long reads
+    ConsensusPoa,
+    /// A partial-order consensus of the members with their homopolymer runs collapsed, each run
+    /// re-expanded to the members' median length; for nanopore amplicons
+    ConsensusHomopolymer,
+}
+
+impl Strategy {
+    /// Whether the cluster is represented by a new consensus record rather
+    /// than by one of its members.
+    pub fn is_consensus(self) -> bool {
+        matches!(self, Strategy::Consensus | Strategy::ConsensusPoa | Strategy::ConsensusHomopolymer)
+    }
+}
+
+/// Largest number of members compared by the medoid strategy.
+const MEDOID_SAMPLE: usize = 100;
+
+/// A cluster member offered for selection: its reads, and what the cluster
+/// file records about it.
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate<'a> {
+    pub pair: &'a PairedRecord,
+    /// Percent identity to the cluster representative, if recorded.
+    pub identity: Option<f64>,
+}
+
+/// Weights of the terms summed by [`Strategy::Weighted`]. Every term lies
+/// between 0 and 1, so the weights say directly how much each one counts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Weights {
+    /// Weight of one minus the mean error probability per base.
+    pub quality: f64,
+    /// Weight of the length relative to the longest member of the cluster.
+    pub length: f64,
+    /// Weight of the identity to the representative as a fraction; 1 for the
+    /// representative itself and for cluster files that record none.
+    pub identity: f64,
+    /// Weight of the sequence [`complexity`], penalising poly-A tails and
+    /// other low-complexity reads in favour of the rest.
+    pub complexity: f64,
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Weights { quality: 1.0, length: 0.0, identity: 0.0, complexity: 0.0 }
+    }
+}
+
+/// Limit on the `N` bases of a member, R1 and R2 combined.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaxN {
+    /// At most this many Ns.
+    Count(usize),
+    /// At most this fraction of the bases are N.
+    Fraction(f64),
+}
+
+impl MaxN {
+    /// Whether `pair` is within the limit.
+    pub fn allows(self, pair: &PairedRecord) -> bool {
+        let n_count = pair.mates().flat_map(|r| r.seq()).filter(|b| b.eq_ignore_ascii_case(&b'N')).count();
+        match self {
+            MaxN::Count(max) => n_count <= max,
+            MaxN::Fraction(max) => n_count as f64 <= max * length(pair) as f64,
+        }
+    }
+}
+
+impl FromStr for MaxN {
+    type Err = String;
+
+    /// Parse a whole number as a count and a number with a decimal point,
+    /// such as `0.05`, as a fraction.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains('.') {
+            match s.parse::<f64>() {
+                Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(MaxN::Fraction(fraction)),
+                _ => Err(format!("{} is not a fraction between 0 and 1", s)),
+            }
+        } else {
+            s.parse().map(MaxN::Count).map_err(|_| format!("{} is not a count or a fraction", s))
+        }
+    }
+}
+
+/// Amplicon primer pairs. A member is anchored when its R1 starts with a
+/// forward primer and the other end of the amplicon carries the reverse primer
+/// of the same pair: at the start of R2, or reverse complemented at the end of
+/// a single-end read. IUPAC codes in the primers match any of their bases.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Primers {
+    pairs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Primers {
+    /// Primer pairs as (forward, reverse), both written 5' to 3'.
+    pub fn new(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Self {
+        let upper = |primer: Vec<u8>| primer.to_ascii_uppercase();
+        Primers { pairs: pairs.into_iter().map(|(forward, reverse)| (upper(forward), upper(reverse))).collect() }
+    }
+
+    /// Whether `pair` is a full amplicon of one of the primer pairs.
+    pub fn anchors(&self, pair: &PairedRecord) -> bool {
+        self.pairs.iter().any(|(forward, reverse)| {
+            primed(pair.r1.seq(), forward)
+                && match &pair.r2 {
+                    Some(r2) => primed(r2.seq(), reverse),
+                    None => {
+                        let seq = pair.r1.seq();
+                        let tail = &seq[seq.len().saturating_sub(reverse.len())..];
+                        primed(&dna::revcomp(tail), reverse)
+                    }
+                }
+        })
+    }
+}
+
+/// Whether `seq` starts with `primer`, IUPAC codes allowed in the primer.
+fn primed(seq: &[u8], primer: &[u8]) -> bool {
+    seq.len() >= primer.len() && seq.iter().zip(primer).all(|(&b, &p)| {
+        let (read, primer) = (iupac_bases(b), iupac_bases(p));
+        read != 0 && read & !primer == 0
+    })
+}
+
+/// The bases an IUPAC code stands for, as a mask with A = 1, C = 2, G = 4 and
+/// T = 8; 0 for anything else.
+pub(crate) fn iupac_bases(code: u8) -> u8 {
+    match code.to_ascii_uppercase() {
+        b'A' => 0b0001,
+        b'C' => 0b0010,
+        b'G' => 0b0100,
+        b'T' | b'U' => 0b1000,
+        b'M' => 0b0011,
+        b'R' => 0b0101,
+        b'W' => 0b1001,
+        b'S' => 0b0110,
+        b'Y' => 0b1010,
+        b'K' => 0b1100,
+        b'V' => 0b0111,
+        b'H' => 0b1011,
+        b'D' => 0b1101,
+        b'B' => 0b1110,
+        b'N' => 0b1111,
+        _ => 0,
+    }
+}
+
+/// The IUPAC code standing for a mask of bases as given by [`iupac_bases`].
+pub(crate) fn iupac_code(bases: u8) -> u8 {
+    b"-ACMGRSVTWYHKDBN"[(bases & 0b1111) as usize]
+}
+
+/// Which mates of a pair are scored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ScoreMate {
+    #[default]
+    Both,
+    R1,
+    /// R2, or R1 for members without a mate
+    R2,
+}
+
+impl ScoreMate {
+    fn mates(self, pair: &PairedRecord) -> impl Iterator<Item = &fastq::Record> {
+        let (r1, r2) = match self {
+            ScoreMate::Both => (Some(&pair.r1), pair.r2.as_ref()),
+            ScoreMate::R1 => (Some(&pair.r1), None),
+            ScoreMate::R2 => (pair.r2.as_ref().or(Some(&pair.r1)), None),
+        };
+        r1.into_iter().chain(r2)
+    }
+}
+
+/// A Trimmomatic-style sliding window, `SLIDINGWINDOW:4:20`: a read is cut
+/// where the mean quality of `size` consecutive bases first drops below
+/// `min_quality`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlidingWindow {
+    pub size: usize,
+    pub min_quality: u8,
+}
+
+impl SlidingWindow {
+    /// Number of leading bases of a read with ASCII qualities `qual`, stored
+    /// with phred offset `offset`, that come before the first failing window.
+    /// Reads shorter than the window are one window.
+    fn kept_len(self, qual: &[u8], offset: u8) -> usize {
+        let size = self.size.min(qual.len());
+        let phred = |i: usize| qual[i].saturating_sub(offset) as usize;
+        let threshold = self.min_quality as usize * size;
+
+        let mut sum: usize = (0..size).map(phred).sum();
+        for start in 0..=qual.len() - size {
+            if start > 0 {
+                sum = sum + phred(start + size - 1) - phred(start - 1);
+            }
+            if sum < threshold {
+                return start;
+            }
+        }
+        qual.len()
+    }
+}
+
+impl FromStr for SlidingWindow {
+    type Err = String;
+
+    /// Parse `<window size>:<minimum mean quality>`, e.g. `4:20`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = s.split_once(':').and_then(|(size, quality)| Some((size.parse().ok()?, quality.parse().ok()?)));
+        match parsed {
+            Some((size, min_quality)) if size > 0 => Ok(SlidingWindow { size, min_quality }),
+            _ => Err(format!("{} is not <window size>:<quality>, e.g. 4:20", s)),
+        }
+    }
+}
+
+/// A caller-supplied scoring function for [`Strategy::Custom`].
+#[derive(Clone)]
+pub struct Scorer(pub Arc<dyn Fn(&PairedRecord) -> f64 + Send + Sync>);
+
+impl fmt::Debug for Scorer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Scorer(..)")
+    }
+}
+
+/// How the representative of a cluster is chosen.
+#[derive(Debug, Clone)]
+pub struct Selection {
+    pub strategy: Strategy,
+    pub score: QualityScore,
+    /// Weights used by [`Strategy::Weighted`].
+    pub weights: Weights,
+    /// Expression ranked on by [`Strategy::Expression`], highest first.
+    pub expression: Option<Expr>,
+    /// Function ranked on by [`Strategy::Custom`], highest first.
+    pub scorer: Option<Scorer>,
+    /// Restrict candidates to the most abundant exact sequence before ranking
+    /// by quality. Pointless for long reads, which are practically never
+    /// identical.
+    pub consensus_vote: bool,
+    /// Sequences within this many substitutions of each other count as
+    /// copies of one another in the vote.
+    pub vote_mismatches: u64,
+    /// The vote only counts if the most abundant sequence has at least this
+    /// many copies...
+    pub vote_min_copies: usize,
+    /// ...and makes up at least this fraction of the cluster. Otherwise the
+    /// best-quality member of the whole cluster is picked.
+    pub vote_min_fraction: f64,
+    /// Pass over members with more ambiguous `N` bases than this.
+    pub max_n: Option<MaxN>,
+    /// Pass over members with any base below this phred quality.
+    pub min_base_quality: Option<u8>,
+    /// Pass over members whose [`complexity`] is below this.
+    pub min_complexity: Option<f64>,
+    /// Pass over members these primers do not anchor.
+    pub primers: Option<Primers>,
+    /// Score members only on the bases before their quality drops off.
+    pub score_trim: Option<SlidingWindow>,
+    /// Bases below this phred quality count against a member in
+    /// [`QualityScore::LowQualityBases`].
+    pub low_quality: u8,
+    /// Mates whose qualities are scored; both are still written.
+    pub score_mate: ScoreMate,
+    /// Rank members from aligned BAM/CRAM input by alignment before quality:
+    /// properly paired first, then mapped, then by mapping quality.
+    pub prefer_aligned: bool,
+    /// ASCII offset of the input qualities, [`PHRED33`] or [`PHRED64`].
+    pub phred_offset: u8,
+}
+
+impl Selection {
+    pub fn short_reads() -> Self {
+        Selection {
+            strategy: Strategy::BestQuality,
+            score: QualityScore::MeanError,
+            weights: Weights::default(),
+            expression: None,
+            scorer: None,
+            consensus_vote: true,
+            vote_mismatches: 0,
+            vote_min_copies: 1,
+            vote_min_fraction: 0.0,
+            max_n: None,
+            min_base_quality: None,
+            min_complexity: None,
+            primers: None,
+            score_trim: None,
+            low_quality: 20,
+            score_mate: ScoreMate::Both,
+            prefer_aligned: false,
+            phred_offset: PHRED33,
+        }
+    }
+
+    pub fn long_reads() -> Self {
+        Selection { score: QualityScore::MedianQuality, consensus_vote: false, ..Selection::short_reads() }
+    }
+
+    /// Rank members by `scorer` instead of a built-in strategy, highest first.
+    /// Ties still go to the first read ID, and `max_n` still applies.
+    ///
+    /// ```
+    /// use clusterpluk::select::Selection;
+    ///
+    /// // Prefer reads close to 250 bases
+    /// let selection = Selection::short_reads().with_scorer(|pair| -(pair.seq().len() as f64 - 250.0).abs());
+    /// ```
+    pub fn with_scorer(self, scorer: impl Fn(&PairedRecord) -> f64 + Send + Sync + 'static) -> Self {
+        Selection { strategy: Strategy::Custom, scorer: Some(Scorer(Arc::new(scorer))), ..self }
+    }
+
+    /// Pick the representative member of a non-empty cluster. The
+    /// representative strategy is resolved by the caller, which knows the
+    /// cluster file; clusters without a marked representative end up here and
+    /// are ranked by quality, as are clusters given to a consensus strategy,
+    /// whose consensus the caller builds with [`crate::consensus`]. Members failing [`Selection::max_n`],
+    /// [`Selection::min_base_quality`], [`Selection::min_complexity`] or
+    /// [`Selection::primers`] are passed over unless that would leave none.
+    pub fn pluck<'a>(&self, cluster: &[Candidate<'a>]) -> &'a PairedRecord {
+        let cluster = self.eligible_members(cluster);
+        let cluster = cluster.as_ref();
+        if cluster.len() == 1 {
+            return cluster[0].pair;
+        }
+        let pairs: Vec<&PairedRecord> = cluster.iter().map(|candidate| candidate.pair).collect();
+        let pairs = pairs.as_slice();
+        match self.strategy {
+            Strategy::MostAbundant => pluck_most_abundant_from_cluster(pairs),
+            Strategy::Longest => self.pluck_longest_from_cluster(pairs),
+            Strategy::Medoid => self.pluck_medoid_from_cluster(pairs),
+            Strategy::MedianLength => self.pluck_median_length_from_cluster(pairs),
+            Strategy::Profile => self.pluck_closest_to_profile(pairs),
+            Strategy::Weighted => self.pluck_weighted_from_cluster(cluster),
+            Strategy::Expression => self.pluck_by_expression(cluster),
+            Strategy::Custom => self.pluck_by_scorer(pairs),
+            Strategy::BestQuality
+            | Strategy::Representative
+            | Strategy::Consensus
+            | Strategy::ConsensusPoa
+            | Strategy::ConsensusHomopolymer => {
+                if pairs.iter().all(|pair| pair.has_qual()) {
+                    self.pluck_best_read_from_cluster(pairs)
+                } else {
+                    pluck_most_abundant_from_cluster(pairs)
+                }
+            }
+        }
+    }
+
+    /// The value `pick` was ranked by among the members of `cluster` by
+    /// [`Selection::pluck`], to report why it won: the quality score (lower
+    /// is better) for the strategies ranking by quality, the number of copies
+    /// of its sequence when those have no qualities to go by and for
+    /// [`Strategy::MostAbundant`], its length for [`Strategy::Longest`] and
+    /// [`Strategy::MedianLength`], its summed edit distance to the others for
+    /// [`Strategy::Medoid`], its distance to the cluster profile for
+    /// [`Strategy::Profile`], and the weighted sum, expression or custom score
+    /// itself for the others. `None` when `pick` has no qualities to score.
+    pub fn score_of(&self, cluster: &[Candidate], candidate: &Candidate) -> Option<f64> {
+        let pick = candidate.pair;
+        let cluster = self.eligible_members(cluster);
+        let cluster = cluster.as_ref();
+        let pairs: Vec<&PairedRecord> = cluster.iter().map(|candidate| candidate.pair).collect();
+        let copies = || {
+            let key = vote_key(&pick.seq());
+            pairs.iter().filter(|pair| vote_key(&pair.seq()) == key).count() as f64
+        };
+        match self.strategy {
+            Strategy::MostAbundant => Some(copies()),
+            Strategy::Longest | Strategy::MedianLength => Some(length(pick) as f64),
+            Strategy::Medoid => {
+                let key = vote_key(&pick.seq());
+                let others = medoid_sample(&pairs);
+                Some(others.iter().map(|pair| levenshtein(&key, &vote_key(&pair.seq())) as f64).sum())
+            }
+            Strategy::Profile => {
+                let (mean_gc, mean_length) = profile(&pairs);
+                Some(profile_distance(pick, mean_gc, mean_length))
+            }
+            Strategy::Weighted => {
+                let has_qual = pairs.iter().all(|pair| pair.has_qual());
+                let longest = pairs.iter().map(|pair| length(pair)).max().unwrap_or(0).max(1);
+                Some(self.weighted_score(candidate, has_qual, longest))
+            }
+            Strategy::Expression => Some(self.expression_value(candidate)),
+            Strategy::Custom => self.scorer.as_ref().map(|Scorer(scorer)| scorer(pick)),
+            Strategy::BestQuality
+            | Strategy::Representative
+            | Strategy::Consensus
+            | Strategy::ConsensusPoa
+            | Strategy::ConsensusHomopolymer => {
+                if !pick.has_qual() {
+                    return None;
+                }
+                if pairs.iter().all(|pair| pair.has_qual()) { Some(self.score(pick)) } else { Some(copies()) }
+            }
+        }
+    }
+
+    /// The members of `cluster` passing the filters of [`Selection::pluck`],
+    /// or all of them if none would.
+    fn eligible_members<'c, 'a>(&self, cluster: &'c [Candidate<'a>]) -> Cow<'c, [Candidate<'a>]> {
+        let filtered = self.max_n.is_some()
+            || self.min_base_quality.is_some()
+            || self.min_complexity.is_some()
+            || self.primers.is_some();
+        if !filtered {
+            return Cow::Borrowed(cluster);
+        }
+        let allowed: Vec<Candidate> =
+            cluster.iter().copied().filter(|candidate| self.eligible(candidate.pair)).collect();
+        if allowed.is_empty() { Cow::Borrowed(cluster) } else { Cow::Owned(allowed) }
+    }
+
+    /// Whether a member is within [`Selection::max_n`], at least
+    /// [`Selection::min_complexity`] and has no base below
+    /// [`Selection::min_base_quality`]. Members without qualities pass the
+    /// latter.
+    fn eligible(&self, pair: &PairedRecord) -> bool {
+        if let Some(max_n) = self.max_n
+            && !max_n.allows(pair)
+        {
+            return false;
+        }
+        if let Some(min) = self.min_complexity
+            && complexity(pair) < min
+        {
+            return false;
+        }
+        if let Some(primers) = &self.primers
+            && !primers.anchors(pair)
+        {
+            return false;
+        }
+        match self.min_base_quality {
+            Some(min) => pair.mates().flat_map(|r| r.qual()).all(|&q| q.saturating_sub(self.phred_offset) >= min),
+            None => true,
+        }
+    }
+
+    /// Mean phred quality of a member over all its mates, or `None` without
+    /// qualities.
+    pub fn mean_quality(&self, pair: &PairedRecord) -> Option<f64> {
+        let qual = pair.qual();
+        if !pair.has_qual() || qual.is_empty() {
+            return None;
+        }
+        let phred_sum: usize = qual.iter().map(|&q| q.saturating_sub(self.phred_offset) as usize).sum();
+        Some(phred_sum as f64 / qual.len() as f64)
+    }
+
+    /// Quality score of a member; lower is better.
+    fn score(&self, pair: &PairedRecord) -> f64 {
+        self.score_with(self.score, pair)
+    }
+
+    /// Score a member with `score`, over its [`Selection::score_mate`] mates
+    /// and the part of each kept by [`Selection::score_trim`] if set.
+    /// Members trimmed away entirely are scored whole.
+    fn score_with(&self, score: QualityScore, pair: &PairedRecord) -> f64 {
+        let mates: Vec<&fastq::Record> = self.score_mate.mates(pair).collect();
+        if let ([read], None) = (mates.as_slice(), self.score_trim) {
+            return score.score(read.seq(), read.qual(), self.phred_offset, self.low_quality);
+        }
+        let kept = |read: &fastq::Record| match self.score_trim {
+            Some(window) => window.kept_len(read.qual(), self.phred_offset),
+            None => read.qual().len(),
+        };
+        let (mut seq, mut qual) = (Vec::new(), Vec::new());
+        for read in &mates {
+            let kept = kept(read);
+            seq.extend_from_slice(&read.seq()[..kept]);
+            qual.extend_from_slice(&read.qual()[..kept]);
+        }
+        if qual.is_empty() {
+            seq = mates.iter().flat_map(|read| read.seq()).copied().collect();
+            qual = mates.iter().flat_map(|read| read.qual()).copied().collect();
+        }
+        score.score(&seq, &qual, self.phred_offset, self.low_quality)
+    }
+
+    /// The best-scoring of a non-empty set of `candidates` from `cluster`, or
+    /// the one with the first read ID when the cluster has no qualities to
+    /// compare.
+    fn best_scoring<'a>(
+        &self,
+        cluster: &[&'a PairedRecord],
+        candidates: impl Iterator<Item = &'a PairedRecord>,
+    ) -> &'a PairedRecord {
+        if cluster.iter().all(|pair| pair.has_qual()) {
+            candidates.min_by(|a, b| self.rank(a, self.score(a), b, self.score(b))).unwrap()
+        } else {
+            candidates.min_by_key(|pair| pair.id()).unwrap()
+        }
+    }
+
+    /// Order two members by score, best first, and members scoring the same
+    /// by read ID so ties are broken the same way on every run. A NaN score,
+    /// e.g. from an expression dividing zero by zero, ranks last. With
+    /// [`Selection::prefer_aligned`] the better alignment goes first.
+    fn rank(&self, a: &PairedRecord, a_score: f64, b: &PairedRecord, b_score: f64) -> Ordering {
+        let by_alignment =
+            if self.prefer_aligned { alignment_rank(b).cmp(&alignment_rank(a)) } else { Ordering::Equal };
+        let by_score = a_score.partial_cmp(&b_score).unwrap_or_else(|| a_score.is_nan().cmp(&b_score.is_nan()));
+        by_alignment.then(by_score).then_with(|| a.id().cmp(b.id()))
+    }
+
+    fn pluck_longest_from_cluster<'a>(&self, cluster: &[&'a PairedRecord]) -> &'a PairedRecord {
+        let longest = cluster.iter().map(|pair| length(pair)).max().unwrap();
+        let candidates = cluster.iter().copied().filter(|pair| length(pair) == longest);
+        self.best_scoring(cluster, candidates)
+    }
+
+    /// The member closest to all others by edit distance. Only an evenly
+    /// spaced sample of [`MEDOID_SAMPLE`] members is compared in larger
+    /// clusters, since the comparison is quadratic in both cluster size and
+    /// read length.
+    fn pluck_medoid_from_cluster<'a>(&self, cluster: &[&'a PairedRecord]) -> &'a PairedRecord {
+        let sample = medoid_sample(cluster);
+        let seqs: Vec<_> = sample.iter().map(|pair| vote_key(&pair.seq())).collect();
+
+        let mut distances = vec![0u64; seqs.len()];
+        for i in 0..seqs.len() {
+            for j in i + 1..seqs.len() {
+                let distance = levenshtein(&seqs[i], &seqs[j]) as u64;
+                distances[i] += distance;
+                distances[j] += distance;
+            }
+        }
+
+        let medoid = *distances.iter().min().unwrap();
+        let candidates = (0..sample.len()).filter(|&i| distances[i] == medoid).map(|i| sample[i]);
+        self.best_scoring(cluster, candidates)
+    }
+
+    /// The member closest to the cluster's mean GC content and length, the
+    /// length measured relative to the mean so both range over fractions.
+    fn pluck_closest_to_profile<'a>(&self, cluster: &[&'a PairedRecord]) -> &'a PairedRecord {
+        let (mean_gc, mean_length) = profile(cluster);
+        let distances: Vec<f64> = cluster.iter().map(|pair| profile_distance(pair, mean_gc, mean_length)).collect();
+        let closest = distances.iter().copied().fold(f64::INFINITY, f64::min);
+        let candidates = (0..cluster.len()).filter(|&i| distances[i] == closest).map(|i| cluster[i]);
+        self.best_scoring(cluster, candidates)
+    }
+
+    /// A member of the median length, the lower median for an even number of
+    /// members so the length is one that occurs in the cluster.
+    fn pluck_median_length_from_cluster<'a>(&self, cluster: &[&'a PairedRecord]) -> &'a PairedRecord {
+        let mut lengths: Vec<usize> = cluster.iter().map(|pair| length(pair)).collect();
+        lengths.sort_unstable();
+        let median = lengths[(lengths.len() - 1) / 2];
+        let candidates = cluster.iter().copied().filter(|pair| length(pair) == median);
+        self.best_scoring(cluster, candidates)
+    }
+
+    /// The member with the highest sum of its weighted terms, see [`Weights`].
+    /// Without qualities the quality term is 0 for every member.
+    fn pluck_weighted_from_cluster<'a>(&self, cluster: &[Candidate<'a>]) -> &'a PairedRecord {
+        let has_qual = cluster.iter().all(|candidate| candidate.pair.has_qual());
+        let longest = cluster.iter().map(|candidate| length(candidate.pair)).max().unwrap().max(1);
+
+        let scores: Vec<f64> =
+            cluster.iter().map(|candidate| self.weighted_score(candidate, has_qual, longest)).collect();
+        let best = (0..scores.len())
+            .min_by(|&i, &j| self.rank(cluster[i].pair, -scores[i], cluster[j].pair, -scores[j]))
+            .unwrap();
+        cluster[best].pair
+    }
+
+    /// The weighted sum of the terms of `candidate`, its length relative to
+    /// `longest` and its quality term 0 unless the cluster `has_qual`.
+    fn weighted_score(&self, candidate: &Candidate, has_qual: bool, longest: usize) -> f64 {
+        let pair = candidate.pair;
+        let quality = if has_qual {
+            1.0 - self.score_with(QualityScore::MeanError, pair)
+        } else {
+            0.0
+        };
+        let relative_length = length(pair) as f64 / longest as f64;
+        let identity = candidate.identity.unwrap_or(100.0) / 100.0;
+        let weights = &self.weights;
+        let complexity = if weights.complexity == 0.0 { 0.0 } else { complexity(pair) };
+        weights.quality * quality
+            + weights.length * relative_length
+            + weights.identity * identity
+            + weights.complexity * complexity
+    }
+
+    fn pluck_by_scorer<'a>(&self, cluster: &[&'a PairedRecord]) -> &'a PairedRecord {
+        let Scorer(scorer) = self.scorer.as_ref().expect("custom strategy without a scorer");
+        let values: Vec<f64> = cluster.iter().map(|pair| scorer(pair)).collect();
+        let best =
+            (0..values.len()).min_by(|&i, &j| self.rank(cluster[i], -values[i], cluster[j], -values[j])).unwrap();
+        cluster[best]
+    }
+
+    /// The member for which [`Selection::expression`] is highest. Members
+    /// without qualities have a `mean_q` and `ee` of 0.
+    fn pluck_by_expression<'a>(&self, cluster: &[Candidate<'a>]) -> &'a PairedRecord {
+        let values: Vec<f64> = cluster.iter().map(|candidate| self.expression_value(candidate)).collect();
+        let best = (0..values.len())
+            .min_by(|&i, &j| self.rank(cluster[i].pair, -values[i], cluster[j].pair, -values[j]))
+            .unwrap();
+        cluster[best].pair
+    }
+
+    /// [`Selection::expression`] evaluated for `candidate`.
+    fn expression_value(&self, candidate: &Candidate) -> f64 {
+        let expression = self.expression.as_ref().expect("expression strategy without an expression");
+        let pair = candidate.pair;
+        let (seq, qual) = (pair.seq(), pair.qual());
+        let has_qual = pair.has_qual() && !qual.is_empty();
+        expression.eval(&Variables {
+            len: seq.len() as f64,
+            mean_q: self.mean_quality(pair).unwrap_or(0.0),
+            ee: if has_qual { expected_errors(&seq, &qual, self.phred_offset) } else { 0.0 },
+            n_count: seq.iter().filter(|b| b.eq_ignore_ascii_case(&b'N')).count() as f64,
+            identity: candidate.identity.unwrap_or(100.0),
+            complexity: complexity(pair),
+        })
+    }
+
+    fn pluck_best_read_from_cluster<'a>(&self, cluster: &[&'a PairedRecord]) -> &'a PairedRecord {
+        let scores: Vec<f64> = cluster.iter().map(|pair| self.score(pair)).collect();
+
+        let mut idxs: Vec<usize> = (0..scores.len()).collect();
+        idxs.sort_by(|&i, &j| self.rank(cluster[i], scores[i], cluster[j], scores[j]));
+
+        if !self.consensus_vote {
+            return cluster[idxs[0]];
+        }
+
+        let seqs: Vec<_> = cluster.iter().map(|pair| vote_key(&pair.seq())).collect();
+        let mut counts: HashMap<&[u8], usize> = HashMap::new();
+        for seq in &seqs {
+            *counts.entry(seq.as_slice()).or_insert(0) += 1;
+        }
+        if self.vote_mismatches > 0 {
+            counts = variant_support(&counts, self.vote_mismatches);
+        }
+
+        let copies = *counts.values().max().unwrap();
+        if copies < self.vote_min_copies || (copies as f64) < self.vote_min_fraction * cluster.len() as f64 {
+            let best = cluster[idxs[0]];
+            debug!(
+                "Most abundant sequence has {} of {} copies in the cluster of {}; picking by quality alone",
+                copies,
+                cluster.len(),
+                best.id()
+            );
+            return best;
+        }
+        // The best-ranked member carrying a most abundant sequence, which also
+        // settles ties between equally abundant sequences
+        let best_index = idxs.into_iter().find(|&i| counts[seqs[i].as_slice()] == copies).unwrap();
+
+        cluster[best_index]
+    }
+}
+
+/// For every sequence variant in `counts`, the number of copies within
+/// `max_mismatches` substitutions of it, counting itself. Variants one
+/// sequencing error apart then vote together instead of splitting the vote,
+/// while the winner is still a sequence that was actually read.
+fn variant_support<'s>(counts: &HashMap<&'s [u8], usize>, max_mismatches: u64) -> HashMap<&'s [u8], usize> {
+    let support = |seq: &[u8]| -> usize {
+        counts
+            .iter()
+            .filter(|(other, _)| other.len() == seq.len() && hamming(seq, other) <= max_mismatches)
+            .map(|(_, &copies)| copies)
+            .sum()
+    };
+    counts.keys().map(|&seq| (seq, support(seq))).collect()
+}
+
+/// Sequence complexity of a member: the Shannon entropy of the trinucleotides
+/// of its mates, scaled from 0 for a homopolymer such as a poly-A tail to 1
+/// for trinucleotides spread as evenly as the length allows. Trinucleotides
+/// with bases other than A, C, G and T are skipped, and members with fewer
+/// than two left count as fully complex.
+pub fn complexity(pair: &PairedRecord) -> f64 {
+    let base_code = |b: &u8| match b.to_ascii_uppercase() {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' => Some(3),
+        _ => None,
+    };
+    let mut counts = [0usize; 64];
+    let mut total = 0;
+    for read in pair.mates() {
+        for triplet in read.seq().windows(3) {
+            if let Some(code) = triplet.iter().try_fold(0, |code, b| Some(code * 4 + base_code(b)?)) {
+                counts[code] += 1;
+                total += 1;
+            }
+        }
+    }
+    if total < 2 {
+        return 1.0;
+    }
+    let entropy: f64 = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total as f64;
+            -p * p.log2()
+        })
+        .sum();
+    entropy / (total.min(counts.len()) as f64).log2()
+}
+
+/// How well a member from aligned input is aligned, higher being better: 2
+/// when every mate is mapped in a proper pair, 1 when every mate is mapped and
+/// 0 otherwise, then the lowest mapping quality of its mates. Members without
+/// alignment tags rank as unmapped.
+fn alignment_rank(pair: &PairedRecord) -> (u8, u8) {
+    let alignments: Option<Vec<Alignment>> =
+        pair.mates().map(|r| r.desc().and_then(Alignment::from_description)).collect();
+    let Some(alignments) = alignments else { return (0, 0) };
+    let mapped = alignments.iter().all(|a| !a.flags.is_unmapped());
+    let proper = mapped && alignments.iter().all(|a| a.flags.is_properly_segmented());
+    let mapping_quality = alignments.iter().map(|a| a.mapping_quality.unwrap_or(0)).min().unwrap_or(0);
+    (mapped as u8 + proper as u8, if mapped { mapping_quality } else { 0 })
+}
+
+/// Length of the k-mers compared by [`kmer_distance`].
+pub const KMER_LEN: usize = 12;
+
+/// The distinct k-mers of a member's mates, ignoring case. Members shorter
+/// than [`KMER_LEN`] have none.
+pub fn kmers(pair: &PairedRecord) -> HashSet<Vec<u8>> {
+    pair.mates().flat_map(|r| r.seq().windows(KMER_LEN)).map(|kmer| kmer.to_ascii_uppercase()).collect()
+}
+
+/// Jaccard distance between two k-mer sets: 0 for the same k-mers, 1 for none
+/// shared.
+pub fn kmer_distance(a: &HashSet<Vec<u8>>, b: &HashSet<Vec<u8>>) -> f64 {
+    let shared = a.intersection(b).count();
+    let union = a.len() + b.len() - shared;
+    if union == 0 { 0.0 } else { 1.0 - shared as f64 / union as f64 }
+}
+
+/// Positions of up to `count` members spanning the diversity of `cluster`, by
+/// farthest-point sampling on [`kmer_distance`]: the first member, then each
+/// time the member farthest from its nearest pick so far, ties going to the
+/// earlier member.
+pub fn farthest_points(cluster: &[&PairedRecord], count: usize) -> Vec<usize> {
+    let kmers: Vec<_> = cluster.iter().map(|pair| kmers(pair)).collect();
+    let mut nearest = vec![f64::INFINITY; cluster.len()];
+    let mut picked = vec![false; cluster.len()];
+    let mut order = Vec::with_capacity(count.min(cluster.len()));
+    let mut next = 0;
+    while order.len() < count && order.len() < cluster.len() {
+        order.push(next);
+        picked[next] = true;
+        for (i, distance) in nearest.iter_mut().enumerate() {
+            *distance = distance.min(kmer_distance(&kmers[next], &kmers[i]));
+        }
+        next = match (0..cluster.len())
+            .filter(|&i| !picked[i])
+            .max_by(|&i, &j| nearest[i].total_cmp(&nearest[j]).then(j.cmp(&i)))
+        {
+            Some(i) => i,
+            None => break,
+        };
+    }
+    order
+}
+
+/// Fraction of the A, C, G and T bases of a member that are G or C.
+fn gc_content(pair: &PairedRecord) -> f64 {
+    let (mut gc, mut acgt) = (0usize, 0usize);
+    for &b in pair.mates().flat_map(|r| r.seq()) {
+        match b.to_ascii_uppercase() {
+            b'G' | b'C' => {
+                gc += 1;
+                acgt += 1;
+            }
+            b'A' | b'T' => acgt += 1,
+            _ => {}
+        }
+    }
+    if acgt == 0 { 0.0 } else { gc as f64 / acgt as f64 }
+}
+
+/// The members the medoid strategy compares: all of them, or an evenly
+/// spaced sample of [`MEDOID_SAMPLE`] in larger clusters.
+fn medoid_sample<'a>(cluster: &[&'a PairedRecord]) -> Vec<&'a PairedRecord> {
+    if cluster.len() > MEDOID_SAMPLE {
+        (0..MEDOID_SAMPLE).map(|i| cluster[i * cluster.len() / MEDOID_SAMPLE]).collect()
+    } else {
+        cluster.to_vec()
+    }
+}
+
+/// Mean GC content and length of a non-empty cluster, the length at least 1.
+fn profile(cluster: &[&PairedRecord]) -> (f64, f64) {
+    let n = cluster.len() as f64;
+    let mean_gc = cluster.iter().map(|pair| gc_content(pair)).sum::<f64>() / n;
+    let mean_length = (cluster.iter().map(|pair| length(pair) as f64).sum::<f64>() / n).max(1.0);
+    (mean_gc, mean_length)
+}
+
+/// Distance of a member to a cluster profile, the length measured relative
+/// to the mean.
+fn profile_distance(pair: &PairedRecord, mean_gc: f64, mean_length: f64) -> f64 {
+    (gc_content(pair) - mean_gc).hypot((length(pair) as f64 - mean_length) / mean_length)
+}
+
+/// Length of a member, R1 and R2 combined.
+fn length(pair: &PairedRecord) -> usize {
+    pair.mates().map(|r| r.seq().len()).sum()
+}
+
+/// Quality-free selection for FASTA input: the most abundant exact sequence
+/// (ignoring case), with ties going to the longest one and then to the first
+/// read ID.
+fn pluck_most_abundant_from_cluster<'a>(cluster: &[&'a PairedRecord]) -> &'a PairedRecord {
+    let seqs: Vec<_> = cluster.iter().map(|pair| vote_key(&pair.seq())).collect();
+
+    let mut counts: HashMap<&[u8], usize> = HashMap::new();
+    for seq in &seqs {
+        *counts.entry(seq.as_slice()).or_insert(0) += 1;
+    }
+
+    let key = |i: usize| (counts[seqs[i].as_slice()], seqs[i].len());
+    let best_index = (0..cluster.len())
+        .max_by(|&i, &j| key(i).cmp(&key(j)).then_with(|| cluster[j].id().cmp(cluster[i].id())))
+        .unwrap();
+
+    cluster[best_index]
+}
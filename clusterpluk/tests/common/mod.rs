@@ -0,0 +1,54 @@
+//! Helpers for running the ClusterPluk binary on small inputs.
+// Every test crate compiles this module but uses only some of it
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// A scratch directory for one test, emptied when created.
+pub struct Scratch {
+    pub dir: PathBuf,
+}
+
+impl Scratch {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("clusterpluk-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Scratch { dir }
+    }
+
+    /// Write `contents` to `name` in the directory and return its path.
+    pub fn file(&self, name: &str, contents: &str) -> String {
+        let path = self.path(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    pub fn path(&self, name: &str) -> String {
+        self.dir.join(name).to_string_lossy().into_owned()
+    }
+
+    /// Run ClusterPluk in the directory with `args`.
+    pub fn run(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_ClusterPluk")).args(args).current_dir(&self.dir).output().unwrap()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A FASTQ with one record per `(id, seq)`, all bases of quality `I`.
+pub fn fastq(records: &[(&str, &str)]) -> String {
+    records.iter().map(|(id, seq)| format!("@{}\n{}\n+\n{}\n", id, seq, "I".repeat(seq.len()))).collect()
+}
+
+/// The IDs of the records of a FASTQ file, without descriptions.
+pub fn ids(path: impl AsRef<Path>) -> Vec<String> {
+    let text = fs::read_to_string(path).unwrap();
+    text.lines().step_by(4).map(|header| header[1..].split(' ').next().unwrap().to_string()).collect()
+}
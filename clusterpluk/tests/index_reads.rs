@@ -0,0 +1,48 @@
+//! I1/I2 index reads stay in sync with picks that are renamed on output.
+
+mod common;
+
+use common::{fastq, ids, Scratch};
+
+const CLSTR: &str = "\
+>Cluster 0
+0\t12nt, >a... *
+1\t12nt, >b... at +/100.00%
+2\t12nt, >c... at +/100.00%
+>Cluster 1
+0\t12nt, >d... *
+";
+
+/// Pick from CLSTR with `extra` options and return the R1 and I1 IDs written.
+fn pick(name: &str, extra: &[&str]) -> (Vec<String>, Vec<String>) {
+    let scratch = Scratch::new(name);
+    let r1 = [("a", "ACGTACGTACGT"), ("b", "ACGTACGTACGA"), ("c", "TTGCATTGCATT"), ("d", "GGCCGGCCGGCC")];
+    let r2 = [("a", "TTTTACGTACGT"), ("b", "TTTTACGTACGA"), ("c", "AAAACATTGCAT"), ("d", "CCCCGGCCGGCC")];
+    let i1 = [("a", "AAAAAAAA"), ("b", "CCCCCCCC"), ("c", "GGGGGGGG"), ("d", "TTTTTTTT")];
+    let r1 = scratch.file("r1.fq", &fastq(&r1));
+    let r2 = scratch.file("r2.fq", &fastq(&r2));
+    let i1 = scratch.file("i1.fq", &fastq(&i1));
+    let clstr = scratch.file("c.clstr", CLSTR);
+    let mut args = vec!["-i", &r1, "--input2", &r2, "-c", &clstr, "-o", "o1.fq", "--output2", "o2.fq"];
+    args.extend(["--i1", &i1, "--output-i1", "oi1.fq"]);
+    args.extend(extra);
+    let output = scratch.run(&args);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    (ids(scratch.path("o1.fq")), ids(scratch.path("oi1.fq")))
+}
+
+#[test]
+fn index_reads_follow_the_size_annotation() {
+    let (r1, i1) = pick("size-annotation", &["--size-annotation"]);
+    assert_eq!(r1.len(), 2);
+    assert!(r1.iter().all(|id| id.contains(";size=")), "{:?}", r1);
+    assert_eq!(i1, r1);
+}
+
+#[test]
+fn index_reads_follow_the_diverse_suffix() {
+    let (r1, i1) = pick("diverse", &["--diverse", "--per-cluster", "2"]);
+    assert_eq!(r1.len(), 3);
+    assert!(r1.iter().all(|id| id.contains("_rep")), "{:?}", r1);
+    assert_eq!(i1, r1);
+}
@@ -0,0 +1,20 @@
+//! Interleaved input with a record missing its mate.
+
+mod common;
+
+use common::{fastq, Scratch};
+
+#[test]
+fn trailing_unpaired_record_is_an_error_not_a_panic() {
+    let scratch = Scratch::new("interleaved-odd");
+    let reads = scratch.file("il.fq", &fastq(&[("a", "ACGTACGT"), ("a", "TTTTACGT"), ("b", "GGGGCCCC")]));
+    let clstr = scratch.file("c.clstr", ">Cluster 0\n0\t8nt, >a... *\n");
+    let output = scratch.run(&["--interleaved", "-i", &reads, "-c", &clstr, "-o", "out.fq"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    assert!(stderr.contains("unpaired record b"), "{}", stderr);
+
+    let output = scratch.run(&["--interleaved", "-i", &reads, "-c", &clstr, "-o", "out.fq", "--ignore-truncation"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
@@ -0,0 +1,27 @@
+//! Options that cannot be shared by the samples of a samplesheet.
+
+mod common;
+
+use common::{fastq, Scratch};
+
+#[test]
+fn per_sample_inputs_conflict_with_a_samplesheet() {
+    let scratch = Scratch::new("samplesheet-inputs");
+    let reads = scratch.file("r.fq", &fastq(&[("a", "ACGTACGT")]));
+    scratch.file("c.clstr", ">Cluster 0\n0\t8nt, >a... *\n");
+    let sheet = scratch.file("s.tsv", "sample\tR1\tR2\tclstr\tout_prefix\nS1\tr.fq\t\tc.clstr\tS1\n");
+    for args in [
+        ["--i1", reads.as_str(), "--output-i1", "i1.fq"],
+        ["--i2", reads.as_str(), "--output-i2", "i2.fq"],
+        ["--singles", reads.as_str(), "--output-singles", "singles.fq"],
+    ] {
+        let output = scratch.run(&[&["--samplesheet", sheet.as_str()], &args[..]].concat());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success(), "{:?} was accepted", args);
+        assert!(stderr.contains("cannot be used with"), "{}", stderr);
+    }
+    // Without them the samplesheet runs
+    let output = scratch.run(&["--samplesheet", &sheet]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(scratch.dir.join("S1.fastq").exists());
+}